@@ -1,2 +1,8 @@
 pub const SOURCE_KEEPER_NAME: &str = "Source Keeper";
-pub const SOURCE_KEEPER_AGRO_RADIUS: u32 = 3;
\ No newline at end of file
+pub const SOURCE_KEEPER_AGRO_RADIUS: u32 = 3;
+pub const ROOM_SIZE: u32 = 50;
+pub const NUKE_BLAST_RADIUS: u32 = 2;
+pub const NUKE_HAZARD_TICKS: u32 = 100;
+pub const POWER_CREEP_HAZARD_RADIUS: u32 = 3;
+pub const MAX_RETARGET_DISTANCE: u32 = 3;
+pub const SPAWN_EXIT_TICKS: u32 = 2;
\ No newline at end of file
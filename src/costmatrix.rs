@@ -1,6 +1,8 @@
+use crate::constants::*;
 use crate::location::*;
 use screeps::pathfinder::*;
 use serde::*;
+use std::borrow::Borrow;
 use std::collections::HashMap;
 
 pub trait CostMatrixApply {
@@ -12,6 +14,11 @@ pub trait CostMatrixApply {
     where
         T: CostMatrixSet,
         TF: Fn(u8) -> u8;
+
+    fn apply_to_filtered<T, P>(&self, target: &mut T, predicate: P)
+    where
+        T: CostMatrixSet,
+        P: Fn(u8, u8, u8) -> bool;
 }
 
 pub trait CostMatrixWrite {
@@ -22,11 +29,172 @@ pub trait CostMatrixRead {
     fn get(&self, x: u8, y: u8) -> u8;
 }
 
+impl CostMatrixWrite for LocalCostMatrix {
+    fn set(&mut self, x: u8, y: u8, val: u8) {
+        LocalCostMatrix::set(self, x, y, val);
+    }
+}
+
+impl CostMatrixRead for LocalCostMatrix {
+    fn get(&self, x: u8, y: u8) -> u8 {
+        LocalCostMatrix::get(self, x, y)
+    }
+}
+
+/// Combines `other` into `target` keeping the higher cost of each tile, so impassable tiles
+/// (`u8::MAX`) are never lowered by a cheaper layer.
+pub fn merge_max<T, S>(target: &mut T, other: &S)
+where
+    T: CostMatrixRead + CostMatrixWrite,
+    S: CostMatrixRead,
+{
+    for y in 0..ROOM_SIZE as u8 {
+        for x in 0..ROOM_SIZE as u8 {
+            let cost = other.get(x, y);
+
+            if cost > target.get(x, y) {
+                target.set(x, y, cost);
+            }
+        }
+    }
+}
+
+/// Caps every cost written through it at `max_cost`. Impassable tiles (`u8::MAX`) are written
+/// unchanged.
+pub struct ClampedCostMatrix<'a, T> {
+    target: &'a mut T,
+    max_cost: u8,
+}
+
+impl<'a, T> ClampedCostMatrix<'a, T> {
+    pub fn new(target: &'a mut T, max_cost: u8) -> Self {
+        ClampedCostMatrix { target, max_cost }
+    }
+}
+
+impl<'a, T> CostMatrixSet for ClampedCostMatrix<'a, T>
+where
+    T: CostMatrixSet,
+{
+    fn set_multi<D, B, P, V>(&mut self, data: D)
+    where
+        D: IntoIterator<Item = B>,
+        B: Borrow<(P, V)>,
+        P: HasLocalPosition,
+        V: Borrow<u8>,
+    {
+        let max_cost = self.max_cost;
+
+        self.target.set_multi(data.into_iter().map(|entry| {
+            let (position, cost) = Borrow::<(P, V)>::borrow(&entry);
+            let cost = *Borrow::<u8>::borrow(cost);
+            let cost = if cost == u8::MAX { cost } else { cost.min(max_cost) };
+
+            (Location::from_coords(position.x() as u32, position.y() as u32), cost)
+        }));
+    }
+}
+
+/// Forwards every cost written through it to `target` and keeps a copy, so costs written by
+/// earlier layers can be read back.
+pub struct RecordingCostMatrix<'a, T> {
+    target: &'a mut T,
+    recorded: DenseCostMatrix,
+}
+
+impl<'a, T> RecordingCostMatrix<'a, T>
+where
+    T: CostMatrixSet,
+{
+    pub fn new(target: &'a mut T) -> Self {
+        RecordingCostMatrix {
+            target,
+            recorded: DenseCostMatrix::new(),
+        }
+    }
+
+    /// Writes `cost` to each tile of `layer` whose recorded cost is lower, never lowering a tile.
+    pub fn raise<S>(&mut self, layer: &S, cost: u8)
+    where
+        S: CostMatrixApply,
+    {
+        let recorded = &self.recorded;
+        let mut raised = LinearCostMatrix::new();
+
+        layer.apply_to_filtered(&mut raised, |x, y, _| recorded.get(x, y) < cost);
+
+        raised.apply_to_transformed(self, |_| cost);
+    }
+}
+
+impl<'a, T> CostMatrixSet for RecordingCostMatrix<'a, T>
+where
+    T: CostMatrixSet,
+{
+    fn set_multi<D, B, P, V>(&mut self, data: D)
+    where
+        D: IntoIterator<Item = B>,
+        B: Borrow<(P, V)>,
+        P: HasLocalPosition,
+        V: Borrow<u8>,
+    {
+        let recorded = &mut self.recorded;
+
+        self.target.set_multi(data.into_iter().map(|entry| {
+            let (position, cost) = Borrow::<(P, V)>::borrow(&entry);
+            let cost = *Borrow::<u8>::borrow(cost);
+
+            recorded.set(position.x(), position.y(), cost);
+
+            (Location::from_coords(position.x() as u32, position.y() as u32), cost)
+        }));
+    }
+}
+
+fn set_multi_with<W, D, B, P, V>(target: &mut W, data: D)
+where
+    W: CostMatrixWrite,
+    D: IntoIterator<Item = B>,
+    B: Borrow<(P, V)>,
+    P: HasLocalPosition,
+    V: Borrow<u8>,
+{
+    for entry in data {
+        let (position, cost) = Borrow::<(P, V)>::borrow(&entry);
+
+        target.set(position.x(), position.y(), *Borrow::<u8>::borrow(cost));
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct SparseCostMatrix {
     data: HashMap<Location, u8>,
 }
 
+impl SparseCostMatrix {
+    pub fn new() -> SparseCostMatrix {
+        SparseCostMatrix {
+            data: HashMap::new(),
+        }
+    }
+
+    pub fn with_capacity(capacity: usize) -> SparseCostMatrix {
+        SparseCostMatrix {
+            data: HashMap::with_capacity(capacity),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.data.clear();
+    }
+}
+
+impl Default for SparseCostMatrix {
+    fn default() -> SparseCostMatrix {
+        SparseCostMatrix::new()
+    }
+}
+
 impl CostMatrixWrite for SparseCostMatrix {
     fn set(&mut self, x: u8, y: u8, val: u8) {
         self.data
@@ -62,9 +230,23 @@ impl CostMatrixApply for SparseCostMatrix {
             (location, new_cost)
         }));
     }
+
+    fn apply_to_filtered<T, P>(&self, target: &mut T, predicate: P)
+    where
+        T: CostMatrixSet,
+        P: Fn(u8, u8, u8) -> bool,
+    {
+        target.set_multi(
+            self.data
+                .iter()
+                .filter(|(location, cost)| predicate(location.x(), location.y(), **cost)),
+        );
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+/// Cost writes stored in insertion order. Writing the same cell more than once keeps every
+/// write, and applying the matrix replays them in order so the last write to a cell wins.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct LinearCostMatrix {
     data: Vec<(Location, u8)>,
 }
@@ -73,6 +255,20 @@ impl LinearCostMatrix {
     pub fn new() -> LinearCostMatrix {
         LinearCostMatrix { data: Vec::new() }
     }
+
+    pub fn clear(&mut self) {
+        self.data.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+impl Default for LinearCostMatrix {
+    fn default() -> LinearCostMatrix {
+        LinearCostMatrix::new()
+    }
 }
 
 impl CostMatrixWrite for LinearCostMatrix {
@@ -82,6 +278,31 @@ impl CostMatrixWrite for LinearCostMatrix {
     }
 }
 
+impl CostMatrixSet for LinearCostMatrix {
+    fn set_multi<D, B, P, V>(&mut self, data: D)
+    where
+        D: IntoIterator<Item = B>,
+        B: Borrow<(P, V)>,
+        P: HasLocalPosition,
+        V: Borrow<u8>,
+    {
+        set_multi_with(self, data);
+    }
+}
+
+impl CostMatrixRead for LinearCostMatrix {
+    fn get(&self, x: u8, y: u8) -> u8 {
+        let location = Location::from_coords(x as u32, y as u32);
+
+        self.data
+            .iter()
+            .rev()
+            .find(|(entry_location, _)| *entry_location == location)
+            .map(|(_, cost)| *cost)
+            .unwrap_or(0)
+    }
+}
+
 impl CostMatrixApply for LinearCostMatrix {
     fn apply_to<T>(&self, target: &mut T)
     where
@@ -101,4 +322,393 @@ impl CostMatrixApply for LinearCostMatrix {
             (location, new_cost)
         }));
     }
+
+    fn apply_to_filtered<T, P>(&self, target: &mut T, predicate: P)
+    where
+        T: CostMatrixSet,
+        P: Fn(u8, u8, u8) -> bool,
+    {
+        target.set_multi(
+            self.data
+                .iter()
+                .filter(|(location, cost)| predicate(location.x(), location.y(), *cost)),
+        );
+    }
+}
+
+const ROOM_AREA: usize = (ROOM_SIZE * ROOM_SIZE) as usize;
+
+pub struct DenseCostMatrix {
+    data: Box<[u8; ROOM_AREA]>,
+}
+
+impl DenseCostMatrix {
+    pub fn new() -> DenseCostMatrix {
+        DenseCostMatrix {
+            data: Box::new([0; ROOM_AREA]),
+        }
+    }
+
+    pub fn from_sparse(source: &SparseCostMatrix) -> DenseCostMatrix {
+        let mut matrix = DenseCostMatrix::new();
+
+        for (location, cost) in source.data.iter() {
+            matrix.set(location.x(), location.y(), *cost);
+        }
+
+        matrix
+    }
+
+    pub fn from_linear(source: &LinearCostMatrix) -> DenseCostMatrix {
+        let mut matrix = DenseCostMatrix::new();
+
+        for (location, cost) in source.data.iter() {
+            matrix.set(location.x(), location.y(), *cost);
+        }
+
+        matrix
+    }
+
+    pub fn clear(&mut self) {
+        self.data.fill(0);
+    }
+
+    #[inline]
+    fn index(x: u8, y: u8) -> usize {
+        assert!(
+            (x as u32) < ROOM_SIZE && (y as u32) < ROOM_SIZE,
+            "coordinates ({}, {}) are outside the room",
+            x,
+            y
+        );
+
+        (y as usize * ROOM_SIZE as usize) + (x as usize)
+    }
+
+    #[inline]
+    fn location(index: usize) -> Location {
+        Location::from_coords(index as u32 % ROOM_SIZE, index as u32 / ROOM_SIZE)
+    }
+
+    fn non_zero(&self) -> impl Iterator<Item = (Location, u8)> + '_ {
+        self.data
+            .iter()
+            .enumerate()
+            .filter(|(_, cost)| **cost > 0)
+            .map(|(index, cost)| (Self::location(index), *cost))
+    }
+}
+
+impl Default for DenseCostMatrix {
+    fn default() -> DenseCostMatrix {
+        DenseCostMatrix::new()
+    }
+}
+
+impl CostMatrixWrite for DenseCostMatrix {
+    fn set(&mut self, x: u8, y: u8, val: u8) {
+        self.data[Self::index(x, y)] = val;
+    }
+}
+
+impl CostMatrixRead for DenseCostMatrix {
+    fn get(&self, x: u8, y: u8) -> u8 {
+        self.data[Self::index(x, y)]
+    }
+}
+
+impl CostMatrixApply for DenseCostMatrix {
+    fn apply_to<T>(&self, target: &mut T)
+    where
+        T: CostMatrixSet,
+    {
+        target.set_multi(self.non_zero());
+    }
+
+    fn apply_to_transformed<T, TF>(&self, target: &mut T, transformer: TF)
+    where
+        T: CostMatrixSet,
+        TF: Fn(u8) -> u8,
+    {
+        target.set_multi(self.non_zero().map(|(location, cost)| {
+            let new_cost = transformer(cost);
+
+            (location, new_cost)
+        }));
+    }
+
+    fn apply_to_filtered<T, P>(&self, target: &mut T, predicate: P)
+    where
+        T: CostMatrixSet,
+        P: Fn(u8, u8, u8) -> bool,
+    {
+        target.set_multi(
+            self.non_zero()
+                .filter(|(location, cost)| predicate(location.x(), location.y(), *cost)),
+        );
+    }
+}
+
+impl Serialize for DenseCostMatrix {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.data[..].serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DenseCostMatrix {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let data = Vec::<u8>::deserialize(deserializer)?;
+
+        if data.len() != ROOM_AREA {
+            return Err(de::Error::invalid_length(data.len(), &"a full room of costs"));
+        }
+
+        let mut matrix = DenseCostMatrix::new();
+
+        matrix.data.copy_from_slice(&data);
+
+        Ok(matrix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct CountingCostMatrix {
+        writes: usize,
+    }
+
+    impl CostMatrixSet for CountingCostMatrix {
+        fn set_multi<D, B, P, V>(&mut self, data: D)
+        where
+            D: IntoIterator<Item = B>,
+            B: Borrow<(P, V)>,
+            P: HasLocalPosition,
+            V: Borrow<u8>,
+        {
+            self.writes += data.into_iter().count();
+        }
+    }
+
+    fn filled_sparse(count: usize) -> SparseCostMatrix {
+        let mut matrix = SparseCostMatrix::new();
+
+        for index in 0..count {
+            let x = (index % ROOM_SIZE as usize) as u8;
+            let y = (index / ROOM_SIZE as usize) as u8;
+
+            matrix.set(x, y, (index % 254) as u8 + 1);
+        }
+
+        matrix
+    }
+
+    #[test]
+    fn dense_apply_matches_sparse_apply() {
+        let sparse = filled_sparse(1200);
+        let dense = DenseCostMatrix::from_sparse(&sparse);
+
+        let mut from_sparse = LocalCostMatrix::new();
+        let mut from_dense = LocalCostMatrix::new();
+
+        sparse.apply_to(&mut from_sparse);
+        dense.apply_to(&mut from_dense);
+
+        for y in 0..ROOM_SIZE as u8 {
+            for x in 0..ROOM_SIZE as u8 {
+                assert_eq!(from_sparse.get(x, y), from_dense.get(x, y));
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "outside the room")]
+    fn dense_rejects_out_of_room_coordinates() {
+        let mut matrix = DenseCostMatrix::new();
+
+        matrix.set(ROOM_SIZE as u8, 0, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "outside the room")]
+    fn dense_rejects_out_of_room_reads() {
+        let matrix = DenseCostMatrix::new();
+
+        matrix.get(0, ROOM_SIZE as u8);
+    }
+
+    #[test]
+    fn linear_read_returns_last_write() {
+        let mut matrix = LinearCostMatrix::new();
+
+        matrix.set(10, 10, 5);
+        matrix.set(10, 10, 200);
+
+        assert_eq!(matrix.get(10, 10), 200);
+        assert_eq!(matrix.get(11, 10), 0);
+    }
+
+    #[test]
+    fn linear_apply_keeps_last_write() {
+        let mut matrix = LinearCostMatrix::new();
+
+        matrix.set(3, 4, 255);
+        matrix.set(3, 4, 7);
+        matrix.set(5, 6, 9);
+
+        let mut target = LocalCostMatrix::new();
+
+        matrix.apply_to(&mut target);
+
+        assert_eq!(target.get(3, 4), 7);
+        assert_eq!(target.get(5, 6), 9);
+    }
+
+    #[test]
+    fn merge_max_keeps_higher_cost_on_overlap() {
+        let mut target = SparseCostMatrix::new();
+        target.set(1, 1, 10);
+        target.set(2, 2, 50);
+
+        let mut other = DenseCostMatrix::new();
+        other.set(1, 1, 30);
+        other.set(2, 2, 20);
+        other.set(3, 3, 5);
+
+        merge_max(&mut target, &other);
+
+        assert_eq!(target.get(1, 1), 30);
+        assert_eq!(target.get(2, 2), 50);
+        assert_eq!(target.get(3, 3), 5);
+    }
+
+    #[test]
+    fn merge_max_never_lowers_impassable() {
+        let mut target = DenseCostMatrix::new();
+        target.set(4, 4, u8::MAX);
+
+        let mut other = SparseCostMatrix::new();
+        other.set(4, 4, 1);
+        other.set(5, 5, u8::MAX);
+
+        merge_max(&mut target, &other);
+
+        assert_eq!(target.get(4, 4), u8::MAX);
+        assert_eq!(target.get(5, 5), u8::MAX);
+    }
+
+    #[test]
+    fn clear_removes_all_costs() {
+        let mut sparse = SparseCostMatrix::new();
+        let mut linear = LinearCostMatrix::new();
+        let mut dense = DenseCostMatrix::new();
+
+        sparse.set(7, 8, 20);
+        linear.set(7, 8, 20);
+        dense.set(7, 8, 20);
+
+        sparse.clear();
+        linear.clear();
+        dense.clear();
+
+        assert_eq!(sparse.get(7, 8), 0);
+        assert_eq!(linear.get(7, 8), 0);
+        assert_eq!(dense.get(7, 8), 0);
+
+        let mut target = CountingCostMatrix::default();
+
+        sparse.apply_to(&mut target);
+        linear.apply_to(&mut target);
+        dense.apply_to(&mut target);
+
+        assert_eq!(target.writes, 0);
+    }
+
+    #[test]
+    fn apply_filtered_writes_only_matching_tiles() {
+        let mut source = SparseCostMatrix::new();
+        source.set(5, 5, 10);
+        source.set(6, 6, 10);
+        source.set(30, 30, 10);
+
+        let mut target = LocalCostMatrix::new();
+
+        source.apply_to_filtered(&mut target, |x, y, _| x < 10 && y < 10);
+
+        assert_eq!(target.get(5, 5), 10);
+        assert_eq!(target.get(6, 6), 10);
+        assert_eq!(target.get(30, 30), 0);
+    }
+
+    #[test]
+    fn sparse_constructors_start_empty() {
+        for mut matrix in vec![
+            SparseCostMatrix::new(),
+            SparseCostMatrix::with_capacity(16),
+            SparseCostMatrix::default(),
+        ] {
+            assert_eq!(matrix.get(12, 34), 0);
+
+            matrix.set(12, 34, 42);
+
+            assert_eq!(matrix.get(12, 34), 42);
+        }
+    }
+
+    #[test]
+    fn merge_max_into_seeded_local_cost_matrix() {
+        let mut target = LocalCostMatrix::new();
+        target.set(1, 2, 40);
+        target.set(3, 4, u8::MAX);
+
+        let mut layer = SparseCostMatrix::new();
+        layer.set(1, 2, 10);
+        layer.set(3, 4, 5);
+        layer.set(5, 6, 60);
+
+        merge_max(&mut target, &layer);
+
+        assert_eq!(target.get(1, 2), 40);
+        assert_eq!(target.get(3, 4), u8::MAX);
+        assert_eq!(target.get(5, 6), 60);
+    }
+
+    #[test]
+    #[ignore]
+    fn dense_apply_is_faster_than_sparse_apply() {
+        use std::time::Instant;
+
+        const ITERATIONS: u32 = 1000;
+
+        let sparse = filled_sparse(ROOM_AREA);
+        let dense = DenseCostMatrix::from_sparse(&sparse);
+        let mut target = LocalCostMatrix::new();
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            sparse.apply_to(&mut target);
+        }
+        let sparse_time = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            dense.apply_to(&mut target);
+        }
+        let dense_time = start.elapsed();
+
+        assert!(
+            dense_time < sparse_time,
+            "dense: {:?}, sparse: {:?}",
+            dense_time,
+            sparse_time
+        );
+    }
 }
@@ -0,0 +1,482 @@
+use super::constants::*;
+use super::costmatrix::*;
+use super::costmatrixsystem::*;
+use super::utility::*;
+use screeps::*;
+use std::collections::HashMap;
+
+pub trait CostMatrixDataSource {
+    fn get_structure_costs(&self, room_name: RoomName) -> Option<StuctureCostMatrixCache>;
+
+    fn get_construction_site_costs(&self, room_name: RoomName) -> Option<ConstructionSiteCostMatrixCache>;
+
+    fn get_creep_costs(&self, room_name: RoomName) -> Option<CreepCostMatrixCache>;
+
+    fn get_terrain_costs(&self, room_name: RoomName) -> Option<TerrainCostMatrixCache>;
+
+    fn get_hazard_costs(&self, room_name: RoomName) -> Option<HazardCostMatrixCache>;
+
+    fn get_creep_positions_hash(&self, _room_name: RoomName) -> Option<u64> {
+        None
+    }
+
+    /// Tick used to age cached layers.
+    fn get_time(&self) -> u32 {
+        game::time()
+    }
+
+    /// Cached layers for a room are only refreshed while it is visible.
+    fn is_room_visible(&self, room_name: RoomName) -> bool {
+        game::rooms::get(room_name).is_some()
+    }
+}
+
+/// Costs of non-road structures. Roads are recorded separately and costed by
+/// `CostMatrixOptions::road_cost` when the matrix is built.
+#[derive(Copy, Clone)]
+pub struct StructureCostConfig {
+    pub container_cost: u8,
+    /// Cost of ramparts that are neither owned nor public. Passable ramparts never add a cost.
+    pub rampart_cost: u8,
+    /// Cost of all other structures.
+    pub wall_cost: u8,
+}
+
+impl Default for StructureCostConfig {
+    fn default() -> Self {
+        StructureCostConfig {
+            container_cost: 2,
+            rampart_cost: u8::MAX,
+            wall_cost: u8::MAX,
+        }
+    }
+}
+
+impl StructureCostConfig {
+    /// Cost of a structure, or `None` if it doesn't affect pathing. `walkable_rampart` is whether
+    /// a rampart is owned or public.
+    pub fn get_cost(&self, structure_type: StructureType, walkable_rampart: bool) -> Option<u8> {
+        match structure_type {
+            StructureType::Rampart => {
+                if walkable_rampart {
+                    None
+                } else {
+                    Some(self.rampart_cost)
+                }
+            }
+            StructureType::Road => None,
+            StructureType::Container => Some(self.container_cost),
+            _ => Some(self.wall_cost),
+        }
+    }
+
+    /// Records a structure at `(x, y)` in the road or structure layer.
+    pub fn set_structure(
+        &self,
+        roads: &mut LinearCostMatrix,
+        other: &mut LinearCostMatrix,
+        structure_type: StructureType,
+        walkable_rampart: bool,
+        x: u8,
+        y: u8,
+    ) {
+        if structure_type == StructureType::Road {
+            roads.set(x, y, 1);
+        } else if let Some(cost) = self.get_cost(structure_type, walkable_rampart) {
+            other.set(x, y, cost);
+        }
+    }
+}
+
+/// Marks every tile within `radius` of `pos` that lies inside the room.
+fn mark_radius(matrix: &mut LinearCostMatrix, pos: Position, radius: u32) {
+    let x = pos.x() as i32;
+    let y = pos.y() as i32;
+
+    for x_offset in x - radius as i32..=x + radius as i32 {
+        for y_offset in y - radius as i32..=y + radius as i32 {
+            if x_offset >= 0 && x_offset < ROOM_SIZE as i32 && y_offset >= 0 && y_offset < ROOM_SIZE as i32 {
+                matrix.set(x_offset as u8, y_offset as u8, 1);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ScreepsCostMatrixDataSource {
+    structure_costs: StructureCostConfig,
+}
+
+impl ScreepsCostMatrixDataSource {
+    pub fn new() -> ScreepsCostMatrixDataSource {
+        ScreepsCostMatrixDataSource::default()
+    }
+
+    pub fn with_structure_costs(structure_costs: StructureCostConfig) -> ScreepsCostMatrixDataSource {
+        ScreepsCostMatrixDataSource { structure_costs }
+    }
+}
+
+impl CostMatrixDataSource for ScreepsCostMatrixDataSource {
+    fn get_structure_costs(&self, room_name: RoomName) -> Option<StuctureCostMatrixCache> {
+        let room = game::rooms::get(room_name)?;
+
+        let mut roads = LinearCostMatrix::new();
+        let mut other = LinearCostMatrix::new();
+
+        let mut hostile_structure_aggro = LinearCostMatrix::new();
+
+        let costs = &self.structure_costs;
+
+        let terrain = game::map::get_room_terrain(room_name);
+
+        let structures = room.find(find::STRUCTURES);
+
+        for structure in structures.iter() {
+            if let Structure::KeeperLair(_) | Structure::InvaderCore(_) = structure {
+                let pos = structure.pos();
+
+                let x = pos.x() as i32;
+                let y = pos.y() as i32;
+
+                for x_offset in x - SOURCE_KEEPER_AGRO_RADIUS as i32..=x + SOURCE_KEEPER_AGRO_RADIUS as i32 {
+                    for y_offset in y - SOURCE_KEEPER_AGRO_RADIUS as i32..=y + SOURCE_KEEPER_AGRO_RADIUS as i32 {
+                        if x_offset >= 0 && x_offset < ROOM_SIZE as i32 && y_offset >= 0 && y_offset < ROOM_SIZE as i32 {
+                            if terrain.get(x_offset as u32, y_offset as u32) != Terrain::Wall {
+                                hostile_structure_aggro.set(x_offset as u8, y_offset as u8, 1);
+                            }
+                        }
+                    }
+                }
+            }
+
+            let structure_type = structure.structure_type();
+
+            let walkable_rampart = match structure {
+                Structure::Rampart(r) => r.my() || r.is_public(),
+                _ => false,
+            };
+
+            let pos = structure.pos();
+
+            costs.set_structure(&mut roads, &mut other, structure_type, walkable_rampart, pos.x() as u8, pos.y() as u8);
+        }
+
+        Some(StuctureCostMatrixCache {
+            roads,
+            other,
+            hostile_structure_aggro,
+        })
+    }
+
+    fn get_construction_site_costs(&self, room_name: RoomName) -> Option<ConstructionSiteCostMatrixCache> {
+        let room = game::rooms::get(room_name)?;
+
+        let mut blocked_construction_sites = LinearCostMatrix::new();
+
+        let mut friendly_inactive_construction_sites = LinearCostMatrix::new();
+        let mut friendly_active_construction_sites = LinearCostMatrix::new();
+
+        let mut hostile_inactive_construction_sites = LinearCostMatrix::new();
+        let mut hostile_active_construction_sites = LinearCostMatrix::new();            
+
+        for construction_site in room.find(find::MY_CONSTRUCTION_SITES).iter() {
+            let pos = construction_site.pos();
+
+            let walkable = match construction_site.structure_type() {
+                StructureType::Container => true,
+                StructureType::Road => true,
+                StructureType::Rampart => true,
+                _ => false
+            };
+
+            if !walkable {
+                blocked_construction_sites.set(pos.x() as u8, pos.y() as u8, u8::MAX);
+            } else if construction_site.progress() > 0 {
+                friendly_active_construction_sites.set(pos.x() as u8, pos.y() as u8, 1);
+            } else {
+                friendly_inactive_construction_sites.set(pos.x() as u8, pos.y() as u8, 1);
+            }
+        }
+
+        let safe_mode = room.controller().and_then(|c| c.safe_mode()).unwrap_or(0) > 0;
+
+        for construction_site in room.find(find::HOSTILE_CONSTRUCTION_SITES).iter() {
+            let pos = construction_site.pos();
+
+            let walkable = !safe_mode;
+
+            if !walkable {
+                blocked_construction_sites.set(pos.x() as u8, pos.y() as u8, u8::MAX);
+            } else if construction_site.progress() > 0 {
+                hostile_active_construction_sites.set(pos.x() as u8, pos.y() as u8, 1);
+            } else {
+                hostile_inactive_construction_sites.set(pos.x() as u8, pos.y() as u8, 1);
+            }
+        }
+
+        Some(ConstructionSiteCostMatrixCache {
+            blocked_construction_sites,
+            friendly_inactive_construction_sites,
+            friendly_active_construction_sites,
+            hostile_inactive_construction_sites,
+            hostile_active_construction_sites
+        })
+    }
+
+    fn get_creep_costs(&self, room_name: RoomName) -> Option<CreepCostMatrixCache> {
+        let room = game::rooms::get(room_name)?;
+
+        let mut friendly_creeps = LinearCostMatrix::new();
+
+        for creep in room.find(find::MY_CREEPS).iter() {
+            let pos = creep.pos();
+
+            friendly_creeps.set(pos.x() as u8, pos.y() as u8, u8::MAX);
+        }
+
+        for power_creep in room.find(find::MY_POWER_CREEPS).iter() {
+            let pos = power_creep.pos();
+
+            friendly_creeps.set(pos.x() as u8, pos.y() as u8, u8::MAX);
+        }
+
+        let mut hostile_creeps = LinearCostMatrix::new();
+
+        let terrain = room.get_terrain();
+        let terrain = terrain.get_raw_buffer();
+
+        let mut source_keeper_agro = LinearCostMatrix::new();            
+
+        for creep in room.find(find::HOSTILE_CREEPS).iter() {
+            let pos = creep.pos();
+
+            hostile_creeps.set(pos.x() as u8, pos.y() as u8, u8::MAX);
+
+            if creep.owner_name() == SOURCE_KEEPER_NAME {
+                let pos = creep.pos();
+
+                let x = pos.x() as i32;
+                let y = pos.y() as i32;
+
+                //TODO: Add constants for room size? Use FastRoomTerrain?
+                
+                for x_offset in x-SOURCE_KEEPER_AGRO_RADIUS as i32..=x+SOURCE_KEEPER_AGRO_RADIUS as i32 {
+                    for y_offset in y-SOURCE_KEEPER_AGRO_RADIUS as i32..=y+SOURCE_KEEPER_AGRO_RADIUS as i32 {
+                        if x_offset >= 0 && x_offset < 50 && y_offset >= 0 && y_offset < 50 {
+                            let index = (y as usize * 50 as usize) + (x as usize);
+
+                            let offset_terrain = terrain[index];
+                            
+                            let is_wall = (offset_terrain & TERRAIN_MASK_WALL) != 0;
+
+                            if !is_wall {
+                                source_keeper_agro.set(x_offset as u8, y_offset as u8, 1);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        for power_creep in room.find(find::HOSTILE_POWER_CREEPS).iter() {
+            let pos = power_creep.pos();
+
+            hostile_creeps.set(pos.x() as u8, pos.y() as u8, u8::MAX);
+        }
+
+        Some(CreepCostMatrixCache {
+            friendly_creeps,
+            hostile_creeps,
+            source_keeper_agro
+        })
+    }
+
+    fn get_creep_positions_hash(&self, room_name: RoomName) -> Option<u64> {
+        let room = game::rooms::get(room_name)?;
+
+        let creeps = room.find(find::CREEPS).iter().map(|creep| creep.pos()).collect::<Vec<_>>();
+        let power_creeps = room.find(find::POWER_CREEPS).iter().map(|creep| creep.pos()).collect::<Vec<_>>();
+
+        Some(hash_value(&(creeps, power_creeps)))
+    }
+
+    fn get_hazard_costs(&self, room_name: RoomName) -> Option<HazardCostMatrixCache> {
+        let room = game::rooms::get(room_name)?;
+
+        let mut nukes = LinearCostMatrix::new();
+
+        //
+        // Only nukes about to land are marked, so paths are not distorted for the whole flight time.
+        //
+
+        for nuke in room.find(find::NUKES).iter() {
+            if nuke.time_to_land() > NUKE_HAZARD_TICKS {
+                continue;
+            }
+
+            mark_radius(&mut nukes, nuke.pos(), NUKE_BLAST_RADIUS);
+        }
+
+        let mut power_creeps = LinearCostMatrix::new();
+
+        for power_creep in room.find(find::HOSTILE_POWER_CREEPS).iter() {
+            mark_radius(&mut power_creeps, power_creep.pos(), POWER_CREEP_HAZARD_RADIUS);
+        }
+
+        Some(HazardCostMatrixCache { nukes, power_creeps })
+    }
+
+    fn get_terrain_costs(&self, room_name: RoomName) -> Option<TerrainCostMatrixCache> {
+        let terrain = game::map::get_room_terrain(room_name);
+        let terrain = terrain.get_raw_buffer();
+
+        let mut swamps = LinearCostMatrix::new();
+
+        for (index, tile) in terrain.iter().enumerate() {
+            let is_wall = (tile & TERRAIN_MASK_WALL) != 0;
+            let is_swamp = (tile & TERRAIN_MASK_SWAMP) != 0;
+
+            if is_swamp && !is_wall {
+                let x = index as u32 % ROOM_SIZE;
+                let y = index as u32 / ROOM_SIZE;
+
+                swamps.set(x as u8, y as u8, 1);
+            }
+        }
+
+        Some(TerrainCostMatrixCache { swamps })
+    }
+}
+
+/// Fixed layers for a set of rooms at a fixed tick. Every room is treated as visible, so
+/// cached layers age purely by `set_time`.
+#[derive(Default)]
+pub struct SnapshotCostMatrixDataSource {
+    time: u32,
+    structures: HashMap<RoomName, StuctureCostMatrixCache>,
+    construction_sites: HashMap<RoomName, ConstructionSiteCostMatrixCache>,
+    creeps: HashMap<RoomName, CreepCostMatrixCache>,
+    terrain: HashMap<RoomName, TerrainCostMatrixCache>,
+    hazards: HashMap<RoomName, HazardCostMatrixCache>,
+}
+
+impl SnapshotCostMatrixDataSource {
+    pub fn new() -> SnapshotCostMatrixDataSource {
+        SnapshotCostMatrixDataSource::default()
+    }
+
+    pub fn set_time(&mut self, time: u32) {
+        self.time = time;
+    }
+
+    pub fn set_structure_costs(&mut self, room_name: RoomName, data: StuctureCostMatrixCache) {
+        self.structures.insert(room_name, data);
+    }
+
+    pub fn set_construction_site_costs(&mut self, room_name: RoomName, data: ConstructionSiteCostMatrixCache) {
+        self.construction_sites.insert(room_name, data);
+    }
+
+    pub fn set_creep_costs(&mut self, room_name: RoomName, data: CreepCostMatrixCache) {
+        self.creeps.insert(room_name, data);
+    }
+
+    pub fn set_terrain_costs(&mut self, room_name: RoomName, data: TerrainCostMatrixCache) {
+        self.terrain.insert(room_name, data);
+    }
+
+    pub fn set_hazard_costs(&mut self, room_name: RoomName, data: HazardCostMatrixCache) {
+        self.hazards.insert(room_name, data);
+    }
+}
+
+impl CostMatrixDataSource for SnapshotCostMatrixDataSource {
+    fn get_structure_costs(&self, room_name: RoomName) -> Option<StuctureCostMatrixCache> {
+        self.structures.get(&room_name).cloned()
+    }
+
+    fn get_construction_site_costs(&self, room_name: RoomName) -> Option<ConstructionSiteCostMatrixCache> {
+        self.construction_sites.get(&room_name).cloned()
+    }
+
+    fn get_creep_costs(&self, room_name: RoomName) -> Option<CreepCostMatrixCache> {
+        self.creeps.get(&room_name).cloned()
+    }
+
+    fn get_terrain_costs(&self, room_name: RoomName) -> Option<TerrainCostMatrixCache> {
+        self.terrain.get(&room_name).cloned()
+    }
+
+    fn get_hazard_costs(&self, room_name: RoomName) -> Option<HazardCostMatrixCache> {
+        self.hazards.get(&room_name).cloned()
+    }
+
+    fn get_time(&self) -> u32 {
+        self.time
+    }
+
+    fn is_room_visible(&self, _room_name: RoomName) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use screeps::pathfinder::LocalCostMatrix;
+
+    #[test]
+    fn structure_costs_follow_config() {
+        let costs = StructureCostConfig {
+            container_cost: 4,
+            rampart_cost: 200,
+            wall_cost: 250,
+        };
+
+        assert_eq!(costs.get_cost(StructureType::Road, false), None);
+        assert_eq!(costs.get_cost(StructureType::Container, false), Some(4));
+        assert_eq!(costs.get_cost(StructureType::Rampart, false), Some(200));
+        assert_eq!(costs.get_cost(StructureType::Rampart, true), None);
+        assert_eq!(costs.get_cost(StructureType::Spawn, false), Some(250));
+        assert_eq!(costs.get_cost(StructureType::Wall, false), Some(250));
+    }
+
+    #[test]
+    fn room_with_container_and_road_uses_configured_costs() {
+        let room_name = RoomName::new("W1N1").unwrap();
+
+        let costs = StructureCostConfig {
+            container_cost: 7,
+            ..StructureCostConfig::default()
+        };
+
+        let mut roads = LinearCostMatrix::new();
+        let mut other = LinearCostMatrix::new();
+
+        costs.set_structure(&mut roads, &mut other, StructureType::Container, false, 10, 10);
+        costs.set_structure(&mut roads, &mut other, StructureType::Road, false, 11, 10);
+
+        let layers = CostMatrixLayers {
+            structures: Some(StuctureCostMatrixCache {
+                roads,
+                other,
+                hostile_structure_aggro: LinearCostMatrix::new(),
+            }),
+            ..CostMatrixLayers::default()
+        };
+
+        let options = CostMatrixOptions {
+            road_cost: 3,
+            ..CostMatrixOptions::default()
+        };
+
+        let mut target = LocalCostMatrix::new();
+
+        CostMatrixCache::apply_from_layers(room_name, &mut target, &layers, &options);
+
+        assert_eq!(target.get(10, 10), 7);
+        assert_eq!(target.get(11, 10), 3);
+        assert_eq!(target.get(12, 10), 0);
+    }
+}
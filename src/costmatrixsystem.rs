@@ -1,61 +1,115 @@
 use super::costmatrix::*;
-use super::constants::*;
-use screeps::pathfinder::CostMatrix;
+use super::costmatrixdatasource::*;
+use super::location::*;
+use screeps::pathfinder::CostMatrixSet;
 use screeps::*;
 use screeps_cache::*;
 use serde::*;
+use std::cell::Cell;
 use std::collections::HashMap;
+use std::collections::HashSet;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CostMatrixTypeCache<T> {
     last_updated: u32,
     data: T,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct StuctureCostMatrixCache {
-    roads: LinearCostMatrix,
-    other: LinearCostMatrix,
+    pub roads: LinearCostMatrix,
+    pub other: LinearCostMatrix,
+    #[serde(default)]
+    pub hostile_structure_aggro: LinearCostMatrix,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ConstructionSiteCostMatrixCache {
-    blocked_construction_sites: LinearCostMatrix,
-    friendly_inactive_construction_sites: LinearCostMatrix,
-    friendly_active_construction_sites: LinearCostMatrix,
-    hostile_inactive_construction_sites: LinearCostMatrix,    
-    hostile_active_construction_sites: LinearCostMatrix,
+    pub blocked_construction_sites: LinearCostMatrix,
+    pub friendly_inactive_construction_sites: LinearCostMatrix,
+    pub friendly_active_construction_sites: LinearCostMatrix,
+    pub hostile_inactive_construction_sites: LinearCostMatrix,
+    pub hostile_active_construction_sites: LinearCostMatrix,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CreepCostMatrixCache {
-    friendly_creeps: LinearCostMatrix,
-    hostile_creeps: LinearCostMatrix,
-    source_keeper_agro: LinearCostMatrix,
+    pub friendly_creeps: LinearCostMatrix,
+    pub hostile_creeps: LinearCostMatrix,
+    pub source_keeper_agro: LinearCostMatrix,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TerrainCostMatrixCache {
+    pub swamps: LinearCostMatrix,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HazardCostMatrixCache {
+    pub nukes: LinearCostMatrix,
+    /// Tiles in range of hostile power creeps.
+    #[serde(default)]
+    pub power_creeps: LinearCostMatrix,
+}
+
+impl HazardCostMatrixCache {
+    pub fn is_empty(&self) -> bool {
+        self.nukes.is_empty() && self.power_creeps.is_empty()
+    }
+}
+
+/// Cached layer a single cell is written into by `CostMatrixSystem::set_cell`.
+pub enum CellLayer {
+    /// Structure costs. Replaced the next time structures are refreshed from the data source.
+    Structures,
+    /// A named layer, created if it doesn't exist.
+    Named(String),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CostMatrixRoomEntry {
     structures: Option<CostMatrixTypeCache<StuctureCostMatrixCache>>,
+    #[serde(default)]
+    named_layers: HashMap<String, LinearCostMatrix>,
     #[serde(skip)]
     construction_sites: Option<CostMatrixTypeCache<ConstructionSiteCostMatrixCache>>,    
     #[serde(skip)]
     creeps: Option<CostMatrixTypeCache<CreepCostMatrixCache>>,
+    #[serde(skip)]
+    terrain: Option<CostMatrixTypeCache<TerrainCostMatrixCache>>,
+    #[serde(skip)]
+    hazards: Option<CostMatrixTypeCache<HazardCostMatrixCache>>,
+    #[serde(skip)]
+    creep_positions_hash: Option<u64>,
+    #[serde(default)]
+    last_accessed: u32,
+    #[serde(skip)]
+    dirty: bool,
 }
 
 impl CostMatrixRoomEntry {
     pub fn new() -> CostMatrixRoomEntry {
         CostMatrixRoomEntry {
             structures: None,
+            named_layers: HashMap::new(),
             construction_sites: None,
             creeps: None,
+            terrain: None,
+            hazards: None,
+            creep_positions_hash: None,
+            last_accessed: 0,
+            dirty: false,
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CostMatrixCache {
     rooms: HashMap<RoomName, CostMatrixRoomEntry>,
+    #[serde(skip)]
+    max_rooms: Option<usize>,
+    #[serde(skip)]
+    removed_rooms: HashSet<RoomName>,
 }
 
 pub trait CostMatrixStorage {
@@ -64,21 +118,42 @@ pub trait CostMatrixStorage {
     fn set_cache(&mut self, segment: u32, data: &CostMatrixCache) -> Result<(), String>;
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone, Hash)]
 pub struct CostMatrixOptions {
     pub structures: bool,
     pub friendly_creeps: bool,
     pub hostile_creeps: bool,
     pub construction_sites: bool,
     pub source_keeper_aggro: bool,
+    /// Raises the cost of tiles around keeper lairs and invader cores.
+    pub hostile_structure_aggro: bool,
+    /// Cost written over road tiles, replacing the terrain cost entirely. A value above
+    /// `plains_cost` makes paths prefer plains over roads; 0 falls back to the terrain cost.
     pub road_cost: u8,
     pub plains_cost: u8,
     pub swamp_cost: u8,
+    /// Marks swamp tiles as near-impassable rather than just raising their cost. Roads built over
+    /// swamp are still usable when structures are applied.
+    pub avoid_swamp: bool,
+    /// Raises the cost of tiles about to be hit by a nuke or in range of a hostile power creep.
+    pub hazards: bool,
+    pub hazard_cost: u8,
     pub source_keeper_aggro_cost: u8,
+    pub hostile_structure_aggro_cost: u8,
+    /// Cost written over hostile creeps instead of their layer cost.
+    pub hostile_creep_cost: Option<u8>,
+    /// Number of ticks the creep layers are kept while no creep in the room has moved. 0 rebuilds
+    /// them every tick.
+    pub idle_creep_cache_ticks: u32,
+    /// Caps every applied cost below `u8::MAX`, so no tile is made near-impassable by a high
+    /// layer cost. Impassable tiles are left blocked.
+    pub max_applied_cost: Option<u8>,
     pub friendly_inactive_construction_site_cost: Option<u8>,
     pub friendly_active_construction_site_cost: Option<u8>,
     pub hostile_inactive_construction_site_cost: Option<u8>,    
     pub hostile_active_construction_site_cost: Option<u8>,
+    pub exempt_construction_site: Option<Position>,
+    pub named_layers: Vec<String>,
 }
 
 impl Default for CostMatrixOptions {
@@ -89,14 +164,42 @@ impl Default for CostMatrixOptions {
             hostile_creeps: true,
             construction_sites: true,
             source_keeper_aggro: true,
+            hostile_structure_aggro: false,
             road_cost: 1,
             plains_cost: 2,
             swamp_cost: 10,
+            avoid_swamp: false,
+            hazards: false,
+            hazard_cost: u8::MAX - 1,
             source_keeper_aggro_cost: 50,
+            hostile_structure_aggro_cost: 50,
+            hostile_creep_cost: None,
+            idle_creep_cache_ticks: 0,
+            max_applied_cost: None,
             friendly_inactive_construction_site_cost: None,
             friendly_active_construction_site_cost: Some(3),
             hostile_inactive_construction_site_cost: Some(2),
             hostile_active_construction_site_cost: Some(1),
+            exempt_construction_site: None,
+            named_layers: Vec::new(),
+        }
+    }
+}
+
+/// Number of ticks each cached layer is kept before being rebuilt. A ttl of 0 is treated as 1.
+#[derive(Copy, Clone)]
+pub struct CostMatrixExpiration {
+    pub structures_ttl: u32,
+    pub construction_sites_ttl: u32,
+    pub creeps_ttl: u32,
+}
+
+impl Default for CostMatrixExpiration {
+    fn default() -> Self {
+        CostMatrixExpiration {
+            structures_ttl: 1,
+            construction_sites_ttl: 1,
+            creeps_ttl: 1,
         }
     }
 }
@@ -104,45 +207,200 @@ impl Default for CostMatrixOptions {
 pub struct CostMatrixSystem {
     storage: Box<dyn CostMatrixStorage>,
     storage_segment: u32,
+    storage_segment_count: u32,
+    data_source: Box<dyn CostMatrixDataSource>,
+    expiration: CostMatrixExpiration,
+    max_rooms: Option<usize>,
     cache: Option<CostMatrixCache>,
+    loaded_shards: HashSet<u32>,
 }
 
 impl CostMatrixSystem {
     pub fn new(storage: Box<dyn CostMatrixStorage>, storage_segment: u32) -> CostMatrixSystem {
+        Self::with_data_source(storage, storage_segment, Box::new(ScreepsCostMatrixDataSource::new()))
+    }
+
+    pub fn with_data_source(
+        storage: Box<dyn CostMatrixStorage>,
+        storage_segment: u32,
+        data_source: Box<dyn CostMatrixDataSource>,
+    ) -> CostMatrixSystem {
         CostMatrixSystem {
             storage,
             storage_segment,
+            storage_segment_count: 1,
+            data_source,
+            expiration: CostMatrixExpiration::default(),
+            max_rooms: None,
             cache: None,
+            loaded_shards: HashSet::new(),
         }
     }
 
+    pub fn set_expiration(&mut self, expiration: CostMatrixExpiration) {
+        self.expiration = expiration;
+    }
+
+    pub fn set_max_rooms(&mut self, max_rooms: Option<usize>) {
+        self.max_rooms = max_rooms;
+    }
+
+    pub fn set_storage_segment_count(&mut self, count: u32) {
+        self.storage_segment_count = count.max(1);
+    }
+
+    /// Writes every segment. Segments that couldn't be read when the cache was loaded are handled as
+    /// in `flush_dirty`, so their stored rooms are never overwritten with an empty cache.
     pub fn flush_storage(&mut self) {
         let storage = &mut self.storage;
-        let cache = &self.cache;
+        let loaded_shards = &mut self.loaded_shards;
         let storage_segment = self.storage_segment;
+        let storage_segment_count = self.storage_segment_count;
+
+        if let Some(cache) = self.cache.as_mut() {
+            let changed_rooms = cache.take_changed_rooms();
+
+            //
+            // Rooms are spread across segments by name so each segment stays within the size limit.
+            //
+
+            for shard in 0..storage_segment_count {
+                let _ = flush_shard(
+                    storage.as_mut(),
+                    cache,
+                    loaded_shards,
+                    storage_segment,
+                    storage_segment_count,
+                    shard,
+                    &changed_rooms,
+                );
+            }
+        }
+    }
 
-        cache
-            .as_ref()
-            .map(|c| storage.set_cache(storage_segment, c));
+    /// Writes only the segments holding rooms that changed since the last flush. A segment that
+    /// couldn't be read when the cache was loaded is read again first so the rooms it holds are kept;
+    /// if it still can't be read it is left untouched, its rooms stay queued and an error is returned.
+    pub fn flush_dirty(&mut self) -> Result<(), String> {
+        let storage = &mut self.storage;
+        let loaded_shards = &mut self.loaded_shards;
+        let storage_segment = self.storage_segment;
+        let storage_segment_count = self.storage_segment_count;
+
+        let cache = match self.cache.as_mut() {
+            Some(cache) => cache,
+            None => return Ok(()),
+        };
+
+        let changed_rooms = cache.take_changed_rooms();
+
+        let changed_shards: HashSet<_> = changed_rooms
+            .iter()
+            .map(|room_name| get_storage_shard(*room_name, storage_segment_count))
+            .collect();
+
+        let mut result = Ok(());
+
+        for shard in changed_shards {
+            if let Err(err) = flush_shard(
+                storage.as_mut(),
+                cache,
+                loaded_shards,
+                storage_segment,
+                storage_segment_count,
+                shard,
+                &changed_rooms,
+            ) {
+                result = Err(err);
+            }
+        }
+
+        result
+    }
+
+    pub fn apply_cost_matrix<T>(
+        &mut self,
+        room_name: RoomName,
+        cost_matrix: &mut T,
+        options: &CostMatrixOptions,
+    ) -> Result<(), String>
+    where
+        T: CostMatrixSet,
+    {
+        let expiration = self.expiration;
+        let (cache, data_source) = self.get_cache_and_data_source();
+
+        cache.apply_cost_matrix(room_name, cost_matrix, options, data_source, expiration)
     }
 
-    pub fn apply_cost_matrix(
+    pub fn apply_dynamic<T>(
         &mut self,
         room_name: RoomName,
-        cost_matrix: &mut CostMatrix,
+        cost_matrix: &mut T,
         options: &CostMatrixOptions,
-    ) -> Result<(), String> {
-        let cache = self.get_cache();
+    ) -> Result<(), String>
+    where
+        T: CostMatrixSet,
+    {
+        let expiration = self.expiration;
+        let (cache, data_source) = self.get_cache_and_data_source();
+
+        cache.apply_dynamic(room_name, cost_matrix, options, data_source, expiration)
+    }
+
+    pub fn set_cell(&mut self, room_name: RoomName, x: u8, y: u8, cost: u8, layer: CellLayer) {
+        let expiration = self.expiration;
+        let (cache, data_source) = self.get_cache_and_data_source();
+
+        cache.get_room(room_name, data_source, expiration).set_cell(x, y, cost, layer);
+    }
+
+    pub fn set_named_layer(&mut self, room_name: RoomName, name: &str, layer: LinearCostMatrix) {
+        let (cache, data_source) = self.get_cache_and_data_source();
+
+        cache.set_named_layer(room_name, name, layer, data_source);
+    }
 
-        cache.apply_cost_matrix(room_name, cost_matrix, options)
+    pub fn remove_named_layer(&mut self, room_name: RoomName, name: &str) -> Option<LinearCostMatrix> {
+        self.get_cache().remove_named_layer(room_name, name)
+    }
+
+    pub fn clear_cache(&mut self) {
+        self.get_cache().clear();
+    }
+
+    pub fn clear_room(&mut self, room_name: RoomName) {
+        self.get_cache().remove_room(room_name);
     }
 
     fn get_cache(&mut self) -> &mut CostMatrixCache {
+        self.get_cache_and_data_source().0
+    }
+
+    fn get_cache_and_data_source(&mut self) -> (&mut CostMatrixCache, &dyn CostMatrixDataSource) {
         let cache = &mut self.cache;
         let storage = &mut self.storage;
+        let loaded_shards = &mut self.loaded_shards;
         let storage_segment = self.storage_segment;
+        let storage_segment_count = self.storage_segment_count;
+
+        let cache = cache.get_or_insert_with(|| {
+            let mut cache = CostMatrixCache::default();
+
+            for shard in 0..storage_segment_count {
+                if let Ok(stored) = storage.get_cache(storage_segment + shard) {
+                    cache.rooms.extend(stored.rooms);
 
-        cache.get_or_insert_with(|| storage.get_cache(storage_segment).unwrap_or_default())
+                    loaded_shards.insert(shard);
+                }
+            }
+
+            cache
+        });
+
+        cache.set_max_rooms(self.max_rooms);
+
+        (cache, self.data_source.as_ref())
     }
 }
 
@@ -150,192 +408,503 @@ impl Default for CostMatrixCache {
     fn default() -> CostMatrixCache {
         CostMatrixCache {
             rooms: HashMap::new(),
+            max_rooms: None,
+            removed_rooms: HashSet::new(),
         }
     }
 }
 
 impl CostMatrixCache {
-    fn get_room(&mut self, room_name: RoomName) -> CostMatrixRoomAccessor {
+    fn get_room<'a>(
+        &'a mut self,
+        room_name: RoomName,
+        data_source: &'a dyn CostMatrixDataSource,
+        expiration: CostMatrixExpiration,
+    ) -> CostMatrixRoomAccessor<'a> {
+        let now = data_source.get_time();
+        let entry = self.get_room_entry(room_name, now);
+
+        CostMatrixRoomAccessor {
+            room_name,
+            entry,
+            data_source,
+            expiration,
+            now,
+        }
+    }
+
+    pub fn set_max_rooms(&mut self, max_rooms: Option<usize>) {
+        self.max_rooms = max_rooms;
+    }
+
+    fn get_room_entry(&mut self, room_name: RoomName, now: u32) -> &mut CostMatrixRoomEntry {
+        if let Some(max_rooms) = self.max_rooms {
+            if !self.rooms.contains_key(&room_name) && self.rooms.len() >= max_rooms {
+                self.evict_least_recently_accessed(now);
+            }
+        }
+
         let entry = self
             .rooms
             .entry(room_name)
             .or_insert_with(CostMatrixRoomEntry::new);
 
-        CostMatrixRoomAccessor { room_name, entry }
+        entry.last_accessed = now;
+
+        entry
+    }
+
+    fn evict_least_recently_accessed(&mut self, now: u32) {
+        //
+        // Rooms used this tick are never evicted, even if that leaves the cache over its limit.
+        //
+
+        let oldest = self
+            .rooms
+            .iter()
+            .filter(|(_, entry)| entry.last_accessed < now)
+            .min_by_key(|(_, entry)| entry.last_accessed)
+            .map(|(room_name, _)| *room_name);
+
+        if let Some(room_name) = oldest {
+            self.remove_room(room_name);
+        }
     }
 
-    pub fn apply_cost_matrix(
+    pub fn set_named_layer(
         &mut self,
         room_name: RoomName,
-        cost_matrix: &mut CostMatrix,
-        options: &CostMatrixOptions,
-    ) -> Result<(), String> {
-        let mut room = self.get_room(room_name);
+        name: &str,
+        layer: LinearCostMatrix,
+        data_source: &dyn CostMatrixDataSource,
+    ) {
+        let entry = self.get_room_entry(room_name, data_source.get_time());
+
+        entry.named_layers.insert(name.to_owned(), layer);
+        entry.dirty = true;
+    }
 
-        if options.structures {
-            if let Some(structures) = room.get_structures() {
-                structures
-                    .roads
-                    .apply_to_transformed(cost_matrix, |_| options.road_cost);
+    pub fn remove_named_layer(&mut self, room_name: RoomName, name: &str) -> Option<LinearCostMatrix> {
+        let entry = self.rooms.get_mut(&room_name)?;
 
-                structures.other.apply_to(cost_matrix);
-            }
+        let layer = entry.named_layers.remove(name);
+
+        if layer.is_some() {
+            entry.dirty = true;
         }
 
-        if options.construction_sites {
-            if let Some(construction_sites) = room.get_construction_sites() {
-                construction_sites.blocked_construction_sites.apply_to(cost_matrix);
+        layer
+    }
 
-                let applicators = [
-                    (options.friendly_inactive_construction_site_cost, &construction_sites.friendly_inactive_construction_sites),
-                    (options.friendly_active_construction_site_cost, &construction_sites.friendly_active_construction_sites),
-                    (options.hostile_inactive_construction_site_cost, &construction_sites.hostile_inactive_construction_sites),
-                    (options.hostile_active_construction_site_cost, &construction_sites.hostile_active_construction_sites),
-                ];
+    pub fn clear(&mut self) {
+        self.removed_rooms.extend(self.rooms.keys().cloned());
 
-                //TODO: Rework API to generate an iterator to batch the full set of cost matrix modifies.
-                for (cost, source_matrix) in &applicators {
-                    if let Some(cost) = cost {
-                        source_matrix.apply_to_transformed(cost_matrix, |_| *cost);
-                    }
-                }        
-            }
+        self.rooms.clear();
+    }
+
+    pub fn remove_room(&mut self, room_name: RoomName) {
+        if self.rooms.remove(&room_name).is_some() {
+            self.removed_rooms.insert(room_name);
         }
+    }
 
-        if options.friendly_creeps || options.hostile_creeps || options.source_keeper_aggro {
-            if let Some(creeps) = room.get_creeps() {
-                if options.source_keeper_aggro {
-                    creeps.source_keeper_agro.apply_to_transformed(cost_matrix, |_| options.source_keeper_aggro_cost)
+    fn requeue_changed_rooms<I>(&mut self, room_names: I)
+    where
+        I: IntoIterator<Item = RoomName>,
+    {
+        for room_name in room_names {
+            match self.rooms.get_mut(&room_name) {
+                Some(entry) => entry.dirty = true,
+                None => {
+                    self.removed_rooms.insert(room_name);
                 }
+            }
+        }
+    }
 
-                if options.friendly_creeps {
-                    creeps.friendly_creeps.apply_to(cost_matrix);
-                }
+    fn take_changed_rooms(&mut self) -> HashSet<RoomName> {
+        let mut changed = std::mem::take(&mut self.removed_rooms);
 
-                if options.hostile_creeps {
-                    creeps.hostile_creeps.apply_to(cost_matrix);
-                }
+        for (room_name, entry) in self.rooms.iter_mut() {
+            if entry.dirty {
+                entry.dirty = false;
+
+                changed.insert(*room_name);
             }
         }
 
+        changed
+    }
+
+    pub fn apply_cost_matrix<T>(
+        &mut self,
+        room_name: RoomName,
+        cost_matrix: &mut T,
+        options: &CostMatrixOptions,
+        data_source: &dyn CostMatrixDataSource,
+        expiration: CostMatrixExpiration,
+    ) -> Result<(), String>
+    where
+        T: CostMatrixSet,
+    {
+        let mut room = self.get_room(room_name, data_source, expiration);
+
+        apply_layers(room_name, &mut room, cost_matrix, options);
+
+        Ok(())
+    }
+
+    pub fn apply_from_layers<T>(
+        room_name: RoomName,
+        cost_matrix: &mut T,
+        layers: &CostMatrixLayers,
+        options: &CostMatrixOptions,
+    ) where
+        T: CostMatrixSet,
+    {
+        let mut layers = layers;
+
+        apply_layers(room_name, &mut layers, cost_matrix, options);
+    }
+
+    pub fn apply_dynamic<T>(
+        &mut self,
+        room_name: RoomName,
+        cost_matrix: &mut T,
+        options: &CostMatrixOptions,
+        data_source: &dyn CostMatrixDataSource,
+        expiration: CostMatrixExpiration,
+    ) -> Result<(), String>
+    where
+        T: CostMatrixSet,
+    {
+        let mut room = self.get_room(room_name, data_source, expiration);
+
+        apply_creep_layers(&mut room, cost_matrix, options);
+
         Ok(())
     }
 }
 
-pub struct CostMatrixRoomAccessor<'a> {
-    room_name: RoomName,
-    entry: &'a mut CostMatrixRoomEntry,
+/// Fixed set of cost matrix layers for a room, applied without access to the game state.
+#[derive(Clone, Default)]
+pub struct CostMatrixLayers {
+    pub terrain: Option<TerrainCostMatrixCache>,
+    pub structures: Option<StuctureCostMatrixCache>,
+    pub construction_sites: Option<ConstructionSiteCostMatrixCache>,
+    pub creeps: Option<CreepCostMatrixCache>,
+    pub hazards: Option<HazardCostMatrixCache>,
+    pub named_layers: HashMap<String, LinearCostMatrix>,
 }
 
-impl<'a> CostMatrixRoomAccessor<'a> {
-    pub fn get_structures(&mut self) -> Option<&StuctureCostMatrixCache> {
-        let room_name = self.room_name;
+trait CostMatrixLayerSource {
+    fn terrain(&mut self) -> Option<&TerrainCostMatrixCache>;
 
-        let expiration = move |data: &CostMatrixTypeCache<_>| {
-            game::time() - data.last_updated > 0 && game::rooms::get(room_name).is_some()
-        };
-        let filler = move || {
-            let room = game::rooms::get(room_name)?;
+    fn structures(&mut self) -> Option<&StuctureCostMatrixCache>;
 
-            let mut roads = LinearCostMatrix::new();
-            let mut other = LinearCostMatrix::new();
+    fn construction_sites(&mut self) -> Option<&ConstructionSiteCostMatrixCache>;
 
-            let structures = room.find(find::STRUCTURES);
+    fn creeps(&mut self, idle_cache_ticks: u32) -> Option<&CreepCostMatrixCache>;
 
-            for structure in structures.iter() {
-                let res = match structure {
-                    Structure::Rampart(r) => {
-                        if r.my() || r.is_public() {
-                            None
-                        } else {
-                            Some((u8::MAX, &mut other))
-                        }
-                    }
-                    Structure::Road(_) => Some((1, &mut roads)),
-                    Structure::Container(_) => Some((2, &mut other)),
-                    _ => Some((u8::MAX, &mut other)),
-                };
+    fn hazards(&mut self) -> Option<&HazardCostMatrixCache>;
 
-                if let Some((cost, matrix)) = res {
-                    let pos = structure.pos();
+    fn named_layer(&self, name: &str) -> Option<&LinearCostMatrix>;
+}
 
-                    matrix.set(pos.x() as u8, pos.y() as u8, cost);
-                }
-            }
+impl<'a> CostMatrixLayerSource for CostMatrixRoomAccessor<'a> {
+    fn terrain(&mut self) -> Option<&TerrainCostMatrixCache> {
+        self.get_terrain()
+    }
 
-            let entry = CostMatrixTypeCache {
-                last_updated: game::time(),
-                data: StuctureCostMatrixCache { roads, other },
-            };
+    fn structures(&mut self) -> Option<&StuctureCostMatrixCache> {
+        self.get_structures()
+    }
 
-            Some(entry)
-        };
+    fn construction_sites(&mut self) -> Option<&ConstructionSiteCostMatrixCache> {
+        self.get_construction_sites()
+    }
 
-        self.entry
-            .structures
-            .maybe_access(expiration, filler)
-            .get()
-            .map(|d| &d.data)
+    fn creeps(&mut self, idle_cache_ticks: u32) -> Option<&CreepCostMatrixCache> {
+        self.get_creeps(idle_cache_ticks)
     }
 
-    pub fn get_construction_sites(&mut self) -> Option<&ConstructionSiteCostMatrixCache> {
-        let room_name = self.room_name;
-        let expiration = |data: &CostMatrixTypeCache<_>| game::time() - data.last_updated > 0 && game::rooms::get(room_name).is_some();
-        let filler = move || {
-            let room = game::rooms::get(room_name)?;
+    fn hazards(&mut self) -> Option<&HazardCostMatrixCache> {
+        self.get_hazards()
+    }
+
+    fn named_layer(&self, name: &str) -> Option<&LinearCostMatrix> {
+        self.get_named_layer(name)
+    }
+}
+
+impl<'a> CostMatrixLayerSource for &'a CostMatrixLayers {
+    fn terrain(&mut self) -> Option<&TerrainCostMatrixCache> {
+        self.terrain.as_ref()
+    }
+
+    fn structures(&mut self) -> Option<&StuctureCostMatrixCache> {
+        self.structures.as_ref()
+    }
+
+    fn construction_sites(&mut self) -> Option<&ConstructionSiteCostMatrixCache> {
+        self.construction_sites.as_ref()
+    }
+
+    fn creeps(&mut self, _idle_cache_ticks: u32) -> Option<&CreepCostMatrixCache> {
+        self.creeps.as_ref()
+    }
+
+    fn hazards(&mut self) -> Option<&HazardCostMatrixCache> {
+        self.hazards.as_ref()
+    }
+
+    fn named_layer(&self, name: &str) -> Option<&LinearCostMatrix> {
+        self.named_layers.get(name)
+    }
+}
+
+fn apply_layers<L, T>(room_name: RoomName, layers: &mut L, cost_matrix: &mut T, options: &CostMatrixOptions)
+where
+    L: CostMatrixLayerSource,
+    T: CostMatrixSet,
+{
+    match options.max_applied_cost {
+        Some(max_cost) => {
+            let mut clamped = ClampedCostMatrix::new(cost_matrix, max_cost);
+
+            apply_all_layers(room_name, layers, &mut clamped, options)
+        }
+        None => apply_all_layers(room_name, layers, cost_matrix, options),
+    }
+}
+
+fn apply_all_layers<L, T>(room_name: RoomName, layers: &mut L, cost_matrix: &mut T, options: &CostMatrixOptions)
+where
+    L: CostMatrixLayerSource,
+    T: CostMatrixSet,
+{
+    let has_hazards = options.hazards && layers.hazards().map(|hazards| !hazards.is_empty()).unwrap_or(false);
+
+    if has_hazards {
+        //
+        // Hazards only raise costs, so tiles blocked by an earlier layer are never made pathable.
+        //
+
+        let mut recording = RecordingCostMatrix::new(cost_matrix);
+
+        apply_base_layers(room_name, layers, &mut recording, options);
+
+        if let Some(hazards) = layers.hazards() {
+            recording.raise(&hazards.nukes, options.hazard_cost);
+            recording.raise(&hazards.power_creeps, options.hazard_cost);
+        }
+
+        apply_named_layers(layers, &mut recording, options);
+    } else {
+        apply_base_layers(room_name, layers, cost_matrix, options);
+        apply_named_layers(layers, cost_matrix, options);
+    }
+}
+
+fn apply_base_layers<L, T>(room_name: RoomName, layers: &mut L, cost_matrix: &mut T, options: &CostMatrixOptions)
+where
+    L: CostMatrixLayerSource,
+    T: CostMatrixSet,
+{
+    if options.avoid_swamp {
+        if let Some(terrain) = layers.terrain() {
+            terrain.swamps.apply_to_transformed(cost_matrix, |_| u8::MAX - 1);
+        }
+    }
+
+    if options.structures {
+        if let Some(structures) = layers.structures() {
+            structures
+                .roads
+                .apply_to_transformed(cost_matrix, |_| options.road_cost);
+
+            if options.hostile_structure_aggro {
+                structures
+                    .hostile_structure_aggro
+                    .apply_to_transformed(cost_matrix, |_| options.hostile_structure_aggro_cost);
+            }
+
+            structures.other.apply_to(cost_matrix);
+        }
+    }
+
+    if options.construction_sites {
+        if let Some(construction_sites) = layers.construction_sites() {
+            let exempt = options
+                .exempt_construction_site
+                .filter(|exempt| exempt.room_name() == room_name)
+                .map(|exempt| (exempt.x() as u8, exempt.y() as u8));
+
+            construction_sites
+                .blocked_construction_sites
+                .apply_to_filtered(cost_matrix, |x, y, _| Some((x, y)) != exempt);
+
+            let applicators = [
+                (options.friendly_inactive_construction_site_cost, &construction_sites.friendly_inactive_construction_sites),
+                (options.friendly_active_construction_site_cost, &construction_sites.friendly_active_construction_sites),
+                (options.hostile_inactive_construction_site_cost, &construction_sites.hostile_inactive_construction_sites),
+                (options.hostile_active_construction_site_cost, &construction_sites.hostile_active_construction_sites),
+            ];
+
+            //TODO: Rework API to generate an iterator to batch the full set of cost matrix modifies.
+            for (cost, source_matrix) in &applicators {
+                if let Some(cost) = cost {
+                    source_matrix.apply_to_transformed(cost_matrix, |_| *cost);
+                }
+            }        
+        }
+    }
+
+    apply_all_creep_layers(layers, cost_matrix, options);
+}
 
-            let mut blocked_construction_sites = LinearCostMatrix::new();
+fn apply_named_layers<L, T>(layers: &mut L, cost_matrix: &mut T, options: &CostMatrixOptions)
+where
+    L: CostMatrixLayerSource,
+    T: CostMatrixSet,
+{
+    for name in &options.named_layers {
+        if let Some(layer) = layers.named_layer(name) {
+            layer.apply_to(cost_matrix);
+        }
+    }
+}
 
-            let mut friendly_inactive_construction_sites = LinearCostMatrix::new();
-            let mut friendly_active_construction_sites = LinearCostMatrix::new();
+fn apply_creep_layers<L, T>(layers: &mut L, cost_matrix: &mut T, options: &CostMatrixOptions)
+where
+    L: CostMatrixLayerSource,
+    T: CostMatrixSet,
+{
+    match options.max_applied_cost {
+        Some(max_cost) => {
+            let mut clamped = ClampedCostMatrix::new(cost_matrix, max_cost);
 
-            let mut hostile_inactive_construction_sites = LinearCostMatrix::new();
-            let mut hostile_active_construction_sites = LinearCostMatrix::new();            
+            apply_all_creep_layers(layers, &mut clamped, options)
+        }
+        None => apply_all_creep_layers(layers, cost_matrix, options),
+    }
+}
 
-            for construction_site in room.find(find::MY_CONSTRUCTION_SITES).iter() {
-                let pos = construction_site.pos();
+fn apply_all_creep_layers<L, T>(layers: &mut L, cost_matrix: &mut T, options: &CostMatrixOptions)
+where
+    L: CostMatrixLayerSource,
+    T: CostMatrixSet,
+{
+    if options.friendly_creeps || options.hostile_creeps || options.source_keeper_aggro {
+        if let Some(creeps) = layers.creeps(options.idle_creep_cache_ticks) {
+            if options.source_keeper_aggro {
+                creeps.source_keeper_agro.apply_to_transformed(cost_matrix, |_| options.source_keeper_aggro_cost)
+            }
 
-                let walkable = match construction_site.structure_type() {
-                    StructureType::Container => true,
-                    StructureType::Road => true,
-                    StructureType::Rampart => true,
-                    _ => false
-                };
+            if options.friendly_creeps {
+                creeps.friendly_creeps.apply_to(cost_matrix);
+            }
 
-                if !walkable {
-                    blocked_construction_sites.set(pos.x() as u8, pos.y() as u8, u8::MAX);
-                } else if construction_site.progress() > 0 {
-                    friendly_active_construction_sites.set(pos.x() as u8, pos.y() as u8, 1);
-                } else {
-                    friendly_inactive_construction_sites.set(pos.x() as u8, pos.y() as u8, 1);
+            if options.hostile_creeps {
+                match options.hostile_creep_cost {
+                    Some(cost) => creeps.hostile_creeps.apply_to_transformed(cost_matrix, |_| cost),
+                    None => creeps.hostile_creeps.apply_to(cost_matrix),
                 }
             }
+        }
+    }
+}
+
+pub struct CostMatrixRoomAccessor<'a> {
+    room_name: RoomName,
+    entry: &'a mut CostMatrixRoomEntry,
+    data_source: &'a dyn CostMatrixDataSource,
+    expiration: CostMatrixExpiration,
+    now: u32,
+}
+
+impl<'a> CostMatrixRoomAccessor<'a> {
+    pub fn get_named_layer(&self, name: &str) -> Option<&LinearCostMatrix> {
+        self.entry.named_layers.get(name)
+    }
+
+    pub fn set_cell(&mut self, x: u8, y: u8, cost: u8, layer: CellLayer) {
+        match layer {
+            CellLayer::Structures => {
+                //
+                // The structure layer is loaded first so the write isn't lost to the initial fill.
+                //
 
-            let safe_mode = room.controller().and_then(|c| c.safe_mode()).unwrap_or(0) > 0;
+                self.get_structures();
 
-            for construction_site in room.find(find::HOSTILE_CONSTRUCTION_SITES).iter() {
-                let pos = construction_site.pos();
+                //
+                // Without visibility there is no structure layer to write into, so the write is dropped.
+                //
 
-                let walkable = !safe_mode;
+                if let Some(structures) = self.entry.structures.as_mut() {
+                    structures.data.other.set(x, y, cost);
 
-                if !walkable {
-                    blocked_construction_sites.set(pos.x() as u8, pos.y() as u8, u8::MAX);
-                } else if construction_site.progress() > 0 {
-                    hostile_active_construction_sites.set(pos.x() as u8, pos.y() as u8, 1);
-                } else {
-                    hostile_inactive_construction_sites.set(pos.x() as u8, pos.y() as u8, 1);
+                    self.entry.dirty = true;
                 }
             }
+            CellLayer::Named(name) => {
+                self.entry
+                    .named_layers
+                    .entry(name)
+                    .or_insert_with(LinearCostMatrix::new)
+                    .set(x, y, cost);
+
+                self.entry.dirty = true;
+            }
+        }
+    }
+
+    pub fn get_structures(&mut self) -> Option<&StuctureCostMatrixCache> {
+        let room_name = self.room_name;
+        let data_source = self.data_source;
+        let ttl = self.expiration.structures_ttl.max(1);
+        let now = self.now;
+
+        let expiration = move |data: &CostMatrixTypeCache<_>| {
+            now.saturating_sub(data.last_updated) >= ttl && data_source.is_room_visible(room_name)
+        };
+        let filler = move || {
+            let entry = CostMatrixTypeCache {
+                last_updated: now,
+                data: data_source.get_structure_costs(room_name)?,
+            };
+
+            Some(entry)
+        };
+
+        let previous_update = self.entry.structures.as_ref().map(|d| d.last_updated);
+
+        self.entry.structures.maybe_access(expiration, filler).get();
+
+        //
+        // Structures are the only persisted layer, so a refill marks the room for the next dirty flush.
+        //
+
+        let current_update = self.entry.structures.as_ref().map(|d| d.last_updated);
+
+        if current_update != previous_update {
+            self.entry.dirty = true;
+        }
+
+        self.entry.structures.as_ref().map(|d| &d.data)
+    }
+
+    pub fn get_construction_sites(&mut self) -> Option<&ConstructionSiteCostMatrixCache> {
+        let room_name = self.room_name;
+        let data_source = self.data_source;
+        let ttl = self.expiration.construction_sites_ttl.max(1);
+        let now = self.now;
 
+        let expiration = |data: &CostMatrixTypeCache<_>| now.saturating_sub(data.last_updated) >= ttl && data_source.is_room_visible(room_name);
+        let filler = move || {
             let entry = CostMatrixTypeCache {
-                last_updated: game::time(),
-                data: ConstructionSiteCostMatrixCache {
-                    blocked_construction_sites,
-                    friendly_inactive_construction_sites,
-                    friendly_active_construction_sites,
-                    hostile_inactive_construction_sites,
-                    hostile_active_construction_sites
-                },
+                last_updated: now,
+                data: data_source.get_construction_site_costs(room_name)?,
             };
 
             Some(entry)
@@ -348,86 +917,970 @@ impl<'a> CostMatrixRoomAccessor<'a> {
             .map(|d| &d.data)
     }
 
-    pub fn get_creeps(&mut self) -> Option<&CreepCostMatrixCache> {
+    pub fn get_creeps(&mut self, idle_cache_ticks: u32) -> Option<&CreepCostMatrixCache> {
         let room_name = self.room_name;
-        let expiration = |data: &CostMatrixTypeCache<_>| game::time() - data.last_updated > 0;
-        let filler = move || {
-            let room = game::rooms::get(room_name)?;
+        let data_source = self.data_source;
+        let ttl = self.expiration.creeps_ttl.max(1);
+        let now = self.now;
 
-            let mut friendly_creeps = LinearCostMatrix::new();
+        //
+        // Creep layers can be kept while no creep has moved, detected by comparing a hash of their positions.
+        //
 
-            for creep in room.find(find::MY_CREEPS).iter() {
-                let pos = creep.pos();
+        let previous_positions_hash = self.entry.creep_positions_hash;
 
-                friendly_creeps.set(pos.x() as u8, pos.y() as u8, u8::MAX);
-            }
+        //
+        // Hashing finds every creep in the room, so it is only done once the layer has outlived its ttl or is rebuilt.
+        //
 
-            for power_creep in room.find(find::MY_POWER_CREEPS).iter() {
-                let pos = power_creep.pos();
+        let positions_hash = Cell::new(None);
 
-                friendly_creeps.set(pos.x() as u8, pos.y() as u8, u8::MAX);
+        let get_positions_hash = || {
+            if idle_cache_ticks == 0 {
+                return None;
             }
 
-            let mut hostile_creeps = LinearCostMatrix::new();
-
-            let terrain = room.get_terrain();
-            let terrain = terrain.get_raw_buffer();
-
-            let mut source_keeper_agro = LinearCostMatrix::new();            
-
-            for creep in room.find(find::HOSTILE_CREEPS).iter() {
-                let pos = creep.pos();
-
-                hostile_creeps.set(pos.x() as u8, pos.y() as u8, u8::MAX);
+            if let Some(hash) = positions_hash.get() {
+                return hash;
+            }
 
-                if creep.owner_name() == SOURCE_KEEPER_NAME {
-                    let pos = creep.pos();
+            let hash = data_source.get_creep_positions_hash(room_name);
 
-                    let x = pos.x() as i32;
-                    let y = pos.y() as i32;
+            positions_hash.set(Some(hash));
 
-                    //TODO: Add constants for room size? Use FastRoomTerrain?
-                    
-                    for x_offset in x-SOURCE_KEEPER_AGRO_RADIUS as i32..=x+SOURCE_KEEPER_AGRO_RADIUS as i32 {
-                        for y_offset in y-SOURCE_KEEPER_AGRO_RADIUS as i32..=y+SOURCE_KEEPER_AGRO_RADIUS as i32 {
-                            if x_offset >= 0 && x_offset < 50 && y_offset >= 0 && y_offset < 50 {
-                                let index = (y as usize * 50 as usize) + (x as usize);
+            hash
+        };
 
-                                let offset_terrain = terrain[index];
-                                
-                                let is_wall = (offset_terrain & TERRAIN_MASK_WALL) != 0;
+        let expiration = |data: &CostMatrixTypeCache<_>| {
+            let age = now.saturating_sub(data.last_updated);
 
-                                if !is_wall {
-                                    source_keeper_agro.set(x_offset as u8, y_offset as u8, 1);
-                                }
-                            }
-                        }
-                    }
-                }
+            if age < ttl {
+                return false;
             }
 
-            for power_creep in room.find(find::HOSTILE_POWER_CREEPS).iter() {
-                let pos = power_creep.pos();
-
-                hostile_creeps.set(pos.x() as u8, pos.y() as u8, u8::MAX);
+            if age > idle_cache_ticks {
+                return true;
             }
 
-            let entry = CostMatrixTypeCache {
-                last_updated: game::time(),
-                data: CreepCostMatrixCache {
-                    friendly_creeps,
-                    hostile_creeps,
-                    source_keeper_agro
-                },
-            };
+            let hash = get_positions_hash();
+
+            hash.is_none() || hash != previous_positions_hash
+        };
+        let filler = move || {
+            let entry = CostMatrixTypeCache {
+                last_updated: now,
+                data: data_source.get_creep_costs(room_name)?,
+            };
+
+            Some(entry)
+        };
+
+        let previous_update = self.entry.creeps.as_ref().map(|d| d.last_updated);
+
+        self.entry.creeps.maybe_access(expiration, filler).get();
+
+        //
+        // The hash is only recorded when the layer is rebuilt, so movement while the layer is still within its ttl is not missed.
+        //
+
+        let current_update = self.entry.creeps.as_ref().map(|d| d.last_updated);
+
+        if current_update != previous_update {
+            self.entry.creep_positions_hash = get_positions_hash();
+        }
+
+        self.entry.creeps.as_ref().map(|d| &d.data)
+    }
+
+    pub fn get_hazards(&mut self) -> Option<&HazardCostMatrixCache> {
+        let room_name = self.room_name;
+        let data_source = self.data_source;
+        let now = self.now;
+
+        let expiration = |data: &CostMatrixTypeCache<_>| now.saturating_sub(data.last_updated) > 0 && data_source.is_room_visible(room_name);
+        let filler = move || {
+            let entry = CostMatrixTypeCache {
+                last_updated: now,
+                data: data_source.get_hazard_costs(room_name)?,
+            };
+
+            Some(entry)
+        };
+
+        self.entry
+            .hazards
+            .maybe_access(expiration, filler)
+            .get()
+            .map(|d| &d.data)
+    }
+
+    pub fn get_terrain(&mut self) -> Option<&TerrainCostMatrixCache> {
+        let room_name = self.room_name;
+        let data_source = self.data_source;
+        let now = self.now;
+
+        let expiration = |_: &CostMatrixTypeCache<_>| false;
+        let filler = move || {
+            let entry = CostMatrixTypeCache {
+                last_updated: now,
+                data: data_source.get_terrain_costs(room_name)?,
+            };
 
             Some(entry)
         };
 
         self.entry
-            .creeps
+            .terrain
             .maybe_access(expiration, filler)
             .get()
             .map(|d| &d.data)
     }
 }
+
+fn get_storage_shard(room_name: RoomName, storage_segment_count: u32) -> u32 {
+    if storage_segment_count <= 1 {
+        0
+    } else {
+        //
+        // Keyed on the room name text rather than a std hash, whose output can change between toolchains and would move rooms to a different segment.
+        //
+
+        let key = room_name
+            .to_string()
+            .bytes()
+            .fold(0u32, |key, byte| key.wrapping_mul(31).wrapping_add(byte as u32));
+
+        key % storage_segment_count
+    }
+}
+
+fn flush_shard(
+    storage: &mut dyn CostMatrixStorage,
+    cache: &mut CostMatrixCache,
+    loaded_shards: &mut HashSet<u32>,
+    storage_segment: u32,
+    storage_segment_count: u32,
+    shard: u32,
+    changed_rooms: &HashSet<RoomName>,
+) -> Result<(), String> {
+    let segment = storage_segment + shard;
+
+    if !loaded_shards.contains(&shard) {
+        match storage.get_cache(segment) {
+            Ok(stored) => {
+                for (room_name, entry) in stored.rooms {
+                    if !changed_rooms.contains(&room_name) {
+                        cache.rooms.entry(room_name).or_insert(entry);
+                    }
+                }
+
+                loaded_shards.insert(shard);
+            }
+            Err(err) => {
+                cache.requeue_changed_rooms(get_shard_rooms(changed_rooms.iter(), shard, storage_segment_count));
+
+                return Err(err);
+            }
+        }
+    }
+
+    //
+    // The shard is written from the in-memory cache, temporarily moving the entries so they don't need to be cloned.
+    //
+
+    let mut stored = CostMatrixCache::default();
+
+    let room_names = get_shard_rooms(cache.rooms.keys(), shard, storage_segment_count);
+
+    for room_name in room_names {
+        if let Some(entry) = cache.rooms.remove(&room_name) {
+            stored.rooms.insert(room_name, entry);
+        }
+    }
+
+    let write_result = storage.set_cache(segment, &stored);
+
+    cache.rooms.extend(stored.rooms);
+
+    if write_result.is_err() {
+        cache.requeue_changed_rooms(get_shard_rooms(changed_rooms.iter(), shard, storage_segment_count));
+    }
+
+    write_result
+}
+
+fn get_shard_rooms<'a, I>(room_names: I, shard: u32, storage_segment_count: u32) -> Vec<RoomName>
+where
+    I: Iterator<Item = &'a RoomName>,
+{
+    room_names
+        .filter(|room_name| get_storage_shard(**room_name, storage_segment_count) == shard)
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use screeps::pathfinder::LocalCostMatrix;
+    use std::cell::Cell;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct MemoryStorage {
+        segments: Rc<RefCell<HashMap<u32, CostMatrixCache>>>,
+        unreadable: Rc<RefCell<HashSet<u32>>>,
+    }
+
+    impl CostMatrixStorage for MemoryStorage {
+        fn get_cache(&self, segment: u32) -> Result<CostMatrixCache, String> {
+            if self.unreadable.borrow().contains(&segment) {
+                return Err(format!("segment {} not loaded", segment));
+            }
+
+            Ok(self.segments.borrow().get(&segment).cloned().unwrap_or_default())
+        }
+
+        fn set_cache(&mut self, segment: u32, data: &CostMatrixCache) -> Result<(), String> {
+            self.segments.borrow_mut().insert(segment, data.clone());
+
+            Ok(())
+        }
+    }
+
+    fn sharded_system(storage: &MemoryStorage) -> CostMatrixSystem {
+        let mut system =
+            CostMatrixSystem::with_data_source(Box::new(storage.clone()), 10, Box::new(SnapshotCostMatrixDataSource::new()));
+
+        system.set_storage_segment_count(4);
+
+        system
+    }
+
+    fn room(name: &str) -> RoomName {
+        RoomName::new(name).unwrap()
+    }
+
+    #[derive(Default)]
+    struct CountingDataSource {
+        time: Cell<u32>,
+        structure_fills: Cell<u32>,
+        creep_fills: Cell<u32>,
+        creep_positions_hash: Cell<Option<u64>>,
+        creep_positions_hash_lookups: Cell<u32>,
+    }
+
+    impl CostMatrixDataSource for CountingDataSource {
+        fn get_structure_costs(&self, _room_name: RoomName) -> Option<StuctureCostMatrixCache> {
+            self.structure_fills.set(self.structure_fills.get() + 1);
+
+            Some(structures(&[], &[]))
+        }
+
+        fn get_construction_site_costs(&self, _room_name: RoomName) -> Option<ConstructionSiteCostMatrixCache> {
+            None
+        }
+
+        fn get_creep_costs(&self, _room_name: RoomName) -> Option<CreepCostMatrixCache> {
+            self.creep_fills.set(self.creep_fills.get() + 1);
+
+            Some(CreepCostMatrixCache {
+                friendly_creeps: LinearCostMatrix::new(),
+                hostile_creeps: LinearCostMatrix::new(),
+                source_keeper_agro: LinearCostMatrix::new(),
+            })
+        }
+
+        fn get_creep_positions_hash(&self, _room_name: RoomName) -> Option<u64> {
+            self.creep_positions_hash_lookups.set(self.creep_positions_hash_lookups.get() + 1);
+
+            self.creep_positions_hash.get()
+        }
+
+        fn get_terrain_costs(&self, _room_name: RoomName) -> Option<TerrainCostMatrixCache> {
+            None
+        }
+
+        fn get_hazard_costs(&self, _room_name: RoomName) -> Option<HazardCostMatrixCache> {
+            None
+        }
+
+        fn get_time(&self) -> u32 {
+            self.time.get()
+        }
+
+        fn is_room_visible(&self, _room_name: RoomName) -> bool {
+            true
+        }
+    }
+
+    fn structure_expiration(structures_ttl: u32) -> CostMatrixExpiration {
+        CostMatrixExpiration {
+            structures_ttl,
+            ..CostMatrixExpiration::default()
+        }
+    }
+
+    fn test_room() -> RoomName {
+        RoomName::new("W1N1").unwrap()
+    }
+
+    fn linear(cells: &[(u8, u8, u8)]) -> LinearCostMatrix {
+        let mut matrix = LinearCostMatrix::new();
+
+        for (x, y, cost) in cells {
+            matrix.set(*x, *y, *cost);
+        }
+
+        matrix
+    }
+
+    fn applied(layers: &CostMatrixLayers, options: &CostMatrixOptions) -> LocalCostMatrix {
+        let mut target = LocalCostMatrix::new();
+
+        CostMatrixCache::apply_from_layers(test_room(), &mut target, layers, options);
+
+        target
+    }
+
+    fn structures(roads: &[(u8, u8, u8)], other: &[(u8, u8, u8)]) -> StuctureCostMatrixCache {
+        StuctureCostMatrixCache {
+            roads: linear(roads),
+            other: linear(other),
+            hostile_structure_aggro: LinearCostMatrix::new(),
+        }
+    }
+
+    #[test]
+    fn snapshot_data_source_applies_without_game() {
+        let mut data_source = SnapshotCostMatrixDataSource::new();
+        data_source.set_time(100);
+        data_source.set_structure_costs(test_room(), structures(&[(10, 10, 1)], &[(11, 11, u8::MAX)]));
+
+        let mut cache = CostMatrixCache::default();
+        let mut target = LocalCostMatrix::new();
+
+        cache
+            .apply_cost_matrix(
+                test_room(),
+                &mut target,
+                &CostMatrixOptions::default(),
+                &data_source,
+                CostMatrixExpiration::default(),
+            )
+            .unwrap();
+
+        assert_eq!(target.get(10, 10), 1);
+        assert_eq!(target.get(11, 11), u8::MAX);
+        assert_eq!(target.get(12, 12), 0);
+    }
+
+    #[test]
+    fn structures_are_kept_for_their_ttl() {
+        let data_source = CountingDataSource::default();
+        let mut cache = CostMatrixCache::default();
+        let mut target = LocalCostMatrix::new();
+
+        for tick in 1000..1099 {
+            data_source.time.set(tick);
+
+            cache
+                .apply_cost_matrix(test_room(), &mut target, &CostMatrixOptions::default(), &data_source, structure_expiration(100))
+                .unwrap();
+        }
+
+        assert_eq!(data_source.structure_fills.get(), 1);
+
+        data_source.time.set(1100);
+
+        cache
+            .apply_cost_matrix(test_room(), &mut target, &CostMatrixOptions::default(), &data_source, structure_expiration(100))
+            .unwrap();
+
+        assert_eq!(data_source.structure_fills.get(), 2);
+    }
+
+    #[test]
+    fn zero_ttl_refills_once_per_tick() {
+        let data_source = CountingDataSource::default();
+        let mut cache = CostMatrixCache::default();
+        let mut target = LocalCostMatrix::new();
+
+        for tick in &[1000, 1000, 1001, 1001] {
+            data_source.time.set(*tick);
+
+            cache
+                .apply_cost_matrix(test_room(), &mut target, &CostMatrixOptions::default(), &data_source, structure_expiration(0))
+                .unwrap();
+        }
+
+        assert_eq!(data_source.structure_fills.get(), 2);
+    }
+
+    #[test]
+    fn idle_creep_layer_is_reused_until_creeps_move() {
+        let data_source = CountingDataSource::default();
+        let mut cache = CostMatrixCache::default();
+        let mut target = LocalCostMatrix::new();
+
+        let expiration = CostMatrixExpiration {
+            creeps_ttl: 3,
+            ..CostMatrixExpiration::default()
+        };
+        let options = CostMatrixOptions {
+            idle_creep_cache_ticks: 10,
+            ..CostMatrixOptions::default()
+        };
+
+        let mut apply = |tick: u32, hash: u64| {
+            data_source.time.set(tick);
+            data_source.creep_positions_hash.set(Some(hash));
+
+            cache
+                .apply_dynamic(test_room(), &mut target, &options, &data_source, expiration)
+                .unwrap();
+
+            data_source.creep_fills.get()
+        };
+
+        assert_eq!(apply(1000, 1), 1);
+
+        //
+        // Creeps moved while the layer was within its ttl, so it must be rebuilt once the ttl passes.
+        //
+
+        assert_eq!(apply(1001, 2), 1);
+        assert_eq!(apply(1003, 2), 2);
+
+        //
+        // No movement since the rebuild keeps the layer past its ttl.
+        //
+
+        assert_eq!(apply(1006, 2), 2);
+        assert_eq!(apply(1010, 3), 3);
+    }
+
+    #[test]
+    fn creep_positions_are_only_hashed_once_the_ttl_passes() {
+        let data_source = CountingDataSource::default();
+        let mut cache = CostMatrixCache::default();
+        let mut target = LocalCostMatrix::new();
+
+        let expiration = CostMatrixExpiration {
+            creeps_ttl: 3,
+            ..CostMatrixExpiration::default()
+        };
+        let options = CostMatrixOptions {
+            idle_creep_cache_ticks: 10,
+            ..CostMatrixOptions::default()
+        };
+
+        data_source.creep_positions_hash.set(Some(1));
+
+        let mut apply = |tick: u32| {
+            data_source.time.set(tick);
+
+            cache
+                .apply_dynamic(test_room(), &mut target, &options, &data_source, expiration)
+                .unwrap();
+
+            data_source.creep_positions_hash_lookups.get()
+        };
+
+        //
+        // The first fill records the hash, searches within the ttl don't look it up again.
+        //
+
+        assert_eq!(apply(1000), 1);
+        assert_eq!(apply(1000), 1);
+        assert_eq!(apply(1002), 1);
+
+        //
+        // Past the ttl the hash is checked once, and the layer is kept as no creep moved.
+        //
+
+        assert_eq!(apply(1003), 2);
+        assert_eq!(data_source.creep_fills.get(), 1);
+
+        //
+        // Without idle caching the hash is never looked up.
+        //
+
+        let options = CostMatrixOptions::default();
+
+        data_source.time.set(1020);
+
+        cache
+            .apply_dynamic(test_room(), &mut target, &options, &data_source, expiration)
+            .unwrap();
+
+        assert_eq!(data_source.creep_positions_hash_lookups.get(), 2);
+    }
+
+    #[test]
+    fn storage_shard_is_stable() {
+        assert_eq!(get_storage_shard(room("W1N1"), 4), 1);
+        assert_eq!(get_storage_shard(room("W2N1"), 4), 2);
+        assert_eq!(get_storage_shard(room("E5S3"), 4), 0);
+        assert_eq!(get_storage_shard(room("W10N10"), 4), 3);
+        assert_eq!(get_storage_shard(room("W10N10"), 1), 0);
+    }
+
+    #[test]
+    fn rooms_round_trip_through_sharded_storage() {
+        let storage = MemoryStorage::default();
+        let names = ["W1N1", "W2N1", "E5S3", "W10N10"];
+
+        let mut system = sharded_system(&storage);
+
+        for name in &names {
+            system.set_named_layer(room(name), "marker", linear(&[(1, 1, 5)]));
+        }
+
+        system.flush_storage();
+
+        for name in &names {
+            let segment = 10 + get_storage_shard(room(name), 4);
+            let segments = storage.segments.borrow();
+
+            assert!(segments[&segment].rooms.contains_key(&room(name)), "room {} missing", name);
+            assert_eq!(segments[&segment].rooms.len(), 1);
+        }
+
+        let mut reloaded = sharded_system(&storage);
+
+        for name in &names {
+            assert!(reloaded.get_cache().rooms[&room(name)].named_layers.contains_key("marker"));
+        }
+    }
+
+    fn seeded_storage(names: &[&str]) -> MemoryStorage {
+        let storage = MemoryStorage::default();
+        let mut system = sharded_system(&storage);
+
+        for name in names {
+            system.set_named_layer(room(name), "marker", linear(&[(1, 1, 5)]));
+        }
+
+        system.flush_storage();
+
+        storage
+    }
+
+    fn stored_markers(storage: &MemoryStorage) -> Vec<(u32, String, u8)> {
+        let mut markers: Vec<_> = storage
+            .segments
+            .borrow()
+            .iter()
+            .flat_map(|(segment, cache)| {
+                cache.rooms.iter().map(move |(room_name, entry)| {
+                    let cost = entry.named_layers.get("marker").map(|layer| layer.get(1, 1)).unwrap_or(0);
+
+                    (*segment, room_name.to_string(), cost)
+                })
+            })
+            .collect();
+
+        markers.sort();
+
+        markers
+    }
+
+    #[test]
+    fn flush_dirty_matches_flush_storage() {
+        let names = ["W1N1", "W2N1", "E5S3", "W10N10"];
+
+        let dirty_storage = seeded_storage(&names);
+        let full_storage = seeded_storage(&names);
+
+        let mut dirty_system = sharded_system(&dirty_storage);
+        let mut full_system = sharded_system(&full_storage);
+
+        for system in &mut [&mut dirty_system, &mut full_system] {
+            system.set_named_layer(room("W1N1"), "marker", linear(&[(1, 1, 9)]));
+            system.set_named_layer(room("W3N3"), "marker", linear(&[(1, 1, 7)]));
+            system.clear_room(room("E5S3"));
+        }
+
+        dirty_system.flush_dirty().unwrap();
+        full_system.flush_storage();
+
+        assert_eq!(stored_markers(&dirty_storage), stored_markers(&full_storage));
+        assert!(!stored_markers(&dirty_storage).iter().any(|(_, name, _)| name == "E5S3"));
+    }
+
+    #[test]
+    fn flush_dirty_skips_unreadable_segments() {
+        let storage = seeded_storage(&["W2N1"]);
+        let unreadable_segment = 10 + get_storage_shard(room("W1N1"), 4);
+
+        storage.unreadable.borrow_mut().insert(unreadable_segment);
+
+        let mut system = sharded_system(&storage);
+
+        system.set_named_layer(room("W1N1"), "marker", linear(&[(1, 1, 9)]));
+
+        assert!(system.flush_dirty().is_err());
+        assert!(!storage.segments.borrow()[&unreadable_segment].rooms.contains_key(&room("W1N1")));
+        assert!(system.get_cache().take_changed_rooms().contains(&room("W1N1")));
+    }
+
+    #[test]
+    fn flush_storage_keeps_rooms_in_unread_segments() {
+        let storage = seeded_storage(&["W1N1", "W2N1"]);
+        let unreadable_segment = 10 + get_storage_shard(room("W1N1"), 4);
+
+        storage.unreadable.borrow_mut().insert(unreadable_segment);
+
+        let mut system = sharded_system(&storage);
+
+        system.set_named_layer(room("W2N1"), "marker", linear(&[(1, 1, 9)]));
+        system.flush_storage();
+
+        assert!(storage.segments.borrow()[&unreadable_segment].rooms.contains_key(&room("W1N1")));
+
+        //
+        // Once the segment can be read its stored rooms are merged before it is written.
+        //
+
+        storage.unreadable.borrow_mut().clear();
+
+        system.flush_storage();
+
+        assert_eq!(
+            stored_markers(&storage),
+            vec![
+                (unreadable_segment, "W1N1".to_owned(), 5),
+                (10 + get_storage_shard(room("W2N1"), 4), "W2N1".to_owned(), 9),
+            ]
+        );
+    }
+
+    #[test]
+    fn hazards_never_unblock_tiles() {
+        let layers = CostMatrixLayers {
+            structures: Some(structures(&[(13, 13, 1)], &[(10, 10, u8::MAX)])),
+            creeps: Some(CreepCostMatrixCache {
+                friendly_creeps: LinearCostMatrix::new(),
+                hostile_creeps: linear(&[(11, 11, u8::MAX)]),
+                source_keeper_agro: LinearCostMatrix::new(),
+            }),
+            hazards: Some(HazardCostMatrixCache {
+                nukes: linear(&[(10, 10, 1), (11, 11, 1), (12, 12, 1), (13, 13, 1)]),
+                power_creeps: LinearCostMatrix::new(),
+            }),
+            ..CostMatrixLayers::default()
+        };
+
+        let options = CostMatrixOptions {
+            hazards: true,
+            ..CostMatrixOptions::default()
+        };
+        let mut target = LocalCostMatrix::new();
+
+        CostMatrixCache::apply_from_layers(test_room(), &mut target, &layers, &options);
+
+        assert_eq!(target.get(10, 10), u8::MAX);
+        assert_eq!(target.get(11, 11), u8::MAX);
+        assert_eq!(target.get(12, 12), options.hazard_cost);
+        assert_eq!(target.get(13, 13), options.hazard_cost);
+    }
+
+    #[test]
+    fn power_creep_hazards_apply_only_when_enabled() {
+        let layers = CostMatrixLayers {
+            hazards: Some(HazardCostMatrixCache {
+                nukes: LinearCostMatrix::new(),
+                power_creeps: linear(&[(20, 20, 1)]),
+            }),
+            ..CostMatrixLayers::default()
+        };
+
+        assert_eq!(applied(&layers, &CostMatrixOptions::default()).get(20, 20), 0);
+
+        let options = CostMatrixOptions {
+            hazards: true,
+            ..CostMatrixOptions::default()
+        };
+
+        assert_eq!(applied(&layers, &options).get(20, 20), options.hazard_cost);
+    }
+
+    #[test]
+    fn set_cell_marks_room_dirty_only_when_written() {
+        let mut data_source = SnapshotCostMatrixDataSource::new();
+        data_source.set_structure_costs(room("W2N1"), structures(&[], &[]));
+
+        let mut cache = CostMatrixCache::default();
+        let expiration = CostMatrixExpiration::default();
+
+        cache.get_room(room("W1N1"), &data_source, expiration).set_cell(5, 5, 20, CellLayer::Structures);
+        cache.get_room(room("W2N1"), &data_source, expiration).set_cell(5, 5, 20, CellLayer::Structures);
+        cache
+            .get_room(room("W3N1"), &data_source, expiration)
+            .set_cell(5, 5, 30, CellLayer::Named("blocked".to_owned()));
+
+        let changed = cache.take_changed_rooms();
+
+        assert!(!changed.contains(&room("W1N1")));
+        assert!(changed.contains(&room("W2N1")));
+        assert!(changed.contains(&room("W3N1")));
+
+        assert_eq!(
+            cache.get_room(room("W2N1"), &data_source, expiration).get_structures().unwrap().other.get(5, 5),
+            20
+        );
+        assert_eq!(
+            cache.get_room(room("W3N1"), &data_source, expiration).get_named_layer("blocked").unwrap().get(5, 5),
+            30
+        );
+    }
+
+    #[test]
+    fn exempt_construction_site_is_passable() {
+        let layers = CostMatrixLayers {
+            construction_sites: Some(ConstructionSiteCostMatrixCache {
+                blocked_construction_sites: linear(&[(5, 5, u8::MAX), (6, 6, u8::MAX)]),
+                friendly_inactive_construction_sites: LinearCostMatrix::new(),
+                friendly_active_construction_sites: LinearCostMatrix::new(),
+                hostile_inactive_construction_sites: LinearCostMatrix::new(),
+                hostile_active_construction_sites: LinearCostMatrix::new(),
+            }),
+            ..CostMatrixLayers::default()
+        };
+
+        let options = CostMatrixOptions {
+            exempt_construction_site: Some(Position::new(5, 5, test_room())),
+            ..CostMatrixOptions::default()
+        };
+
+        let target = applied(&layers, &options);
+
+        assert_eq!(target.get(5, 5), 0);
+        assert_eq!(target.get(6, 6), u8::MAX);
+
+        let other_room = CostMatrixOptions {
+            exempt_construction_site: Some(Position::new(5, 5, room("W2N1"))),
+            ..CostMatrixOptions::default()
+        };
+
+        assert_eq!(applied(&layers, &other_room).get(5, 5), u8::MAX);
+    }
+
+    #[test]
+    fn exempt_construction_site_keeps_structure_costs() {
+        let layers = CostMatrixLayers {
+            structures: Some(structures(&[], &[(5, 5, 200)])),
+            construction_sites: Some(ConstructionSiteCostMatrixCache {
+                blocked_construction_sites: linear(&[(5, 5, u8::MAX)]),
+                friendly_inactive_construction_sites: LinearCostMatrix::new(),
+                friendly_active_construction_sites: LinearCostMatrix::new(),
+                hostile_inactive_construction_sites: LinearCostMatrix::new(),
+                hostile_active_construction_sites: LinearCostMatrix::new(),
+            }),
+            ..CostMatrixLayers::default()
+        };
+
+        let options = CostMatrixOptions {
+            exempt_construction_site: Some(Position::new(5, 5, test_room())),
+            ..CostMatrixOptions::default()
+        };
+
+        assert_eq!(applied(&layers, &options).get(5, 5), 200);
+    }
+
+    #[test]
+    fn named_layers_apply_only_when_requested() {
+        let mut layers = CostMatrixLayers::default();
+        layers.named_layers.insert("avoid".to_owned(), linear(&[(20, 20, 100)]));
+        layers.named_layers.insert("other".to_owned(), linear(&[(21, 21, 100)]));
+
+        let options = CostMatrixOptions {
+            named_layers: vec!["avoid".to_owned(), "missing".to_owned()],
+            ..CostMatrixOptions::default()
+        };
+
+        let target = applied(&layers, &options);
+
+        assert_eq!(target.get(20, 20), 100);
+        assert_eq!(target.get(21, 21), 0);
+
+        assert_eq!(applied(&layers, &CostMatrixOptions::default()).get(20, 20), 0);
+    }
+
+    #[test]
+    fn high_road_cost_makes_roads_dearer_than_plains() {
+        let layers = CostMatrixLayers {
+            structures: Some(structures(&[(1, 1, 1), (2, 1, 1)], &[])),
+            ..CostMatrixLayers::default()
+        };
+
+        let options = CostMatrixOptions {
+            road_cost: 20,
+            ..CostMatrixOptions::default()
+        };
+
+        let target = applied(&layers, &options);
+
+        assert_eq!(target.get(1, 1), 20);
+        assert_eq!(target.get(2, 1), 20);
+        assert!(target.get(1, 1) > options.plains_cost);
+
+        //
+        // Plains are left to the terrain cost.
+        //
+
+        assert_eq!(target.get(1, 2), 0);
+    }
+
+    #[test]
+    fn apply_dynamic_writes_only_creep_tiles() {
+        let mut data_source = SnapshotCostMatrixDataSource::new();
+        data_source.set_structure_costs(test_room(), structures(&[(1, 1, 1)], &[(2, 2, u8::MAX)]));
+        data_source.set_creep_costs(
+            test_room(),
+            CreepCostMatrixCache {
+                friendly_creeps: LinearCostMatrix::new(),
+                hostile_creeps: linear(&[(3, 3, u8::MAX)]),
+                source_keeper_agro: LinearCostMatrix::new(),
+            },
+        );
+
+        let mut cache = CostMatrixCache::default();
+        let mut target = LocalCostMatrix::new();
+
+        cache
+            .apply_dynamic(
+                test_room(),
+                &mut target,
+                &CostMatrixOptions::default(),
+                &data_source,
+                CostMatrixExpiration::default(),
+            )
+            .unwrap();
+
+        assert_eq!(target.get(1, 1), 0);
+        assert_eq!(target.get(2, 2), 0);
+        assert_eq!(target.get(3, 3), u8::MAX);
+    }
+
+    #[test]
+    fn avoid_swamp_blocks_swamps_except_under_roads() {
+        let layers = CostMatrixLayers {
+            terrain: Some(TerrainCostMatrixCache {
+                swamps: linear(&[(2, 2, 1), (3, 3, 1)]),
+            }),
+            structures: Some(structures(&[(3, 3, 1)], &[])),
+            ..CostMatrixLayers::default()
+        };
+
+        let avoid = CostMatrixOptions {
+            avoid_swamp: true,
+            ..CostMatrixOptions::default()
+        };
+
+        let target = applied(&layers, &avoid);
+
+        assert_eq!(target.get(2, 2), u8::MAX - 1);
+        assert_eq!(target.get(3, 3), avoid.road_cost);
+
+        assert_eq!(applied(&layers, &CostMatrixOptions::default()).get(2, 2), 0);
+    }
+
+    #[test]
+    fn hostile_structure_aggro_applies_only_when_enabled() {
+        let mut aggro = structures(&[], &[]);
+        aggro.hostile_structure_aggro = linear(&[(8, 8, 1)]);
+
+        let layers = CostMatrixLayers {
+            structures: Some(aggro),
+            ..CostMatrixLayers::default()
+        };
+
+        let enabled = CostMatrixOptions {
+            hostile_structure_aggro: true,
+            hostile_structure_aggro_cost: 80,
+            ..CostMatrixOptions::default()
+        };
+
+        assert_eq!(applied(&layers, &enabled).get(8, 8), 80);
+        assert_eq!(applied(&layers, &CostMatrixOptions::default()).get(8, 8), 0);
+    }
+
+    #[test]
+    fn layers_stack_in_order() {
+        let mut named_layers = HashMap::new();
+        named_layers.insert("override".to_owned(), linear(&[(4, 4, 60)]));
+
+        let layers = CostMatrixLayers {
+            terrain: Some(TerrainCostMatrixCache {
+                swamps: linear(&[(1, 1, 1), (2, 2, 1), (3, 3, 1), (4, 4, 1)]),
+            }),
+            structures: Some(structures(&[(2, 2, 1), (3, 3, 1), (4, 4, 1)], &[])),
+            creeps: Some(CreepCostMatrixCache {
+                friendly_creeps: LinearCostMatrix::new(),
+                hostile_creeps: linear(&[(3, 3, u8::MAX), (4, 4, u8::MAX)]),
+                source_keeper_agro: LinearCostMatrix::new(),
+            }),
+            named_layers,
+            ..CostMatrixLayers::default()
+        };
+
+        let options = CostMatrixOptions {
+            avoid_swamp: true,
+            named_layers: vec!["override".to_owned()],
+            ..CostMatrixOptions::default()
+        };
+
+        let target = applied(&layers, &options);
+
+        //
+        // Terrain, then structures, then creeps, then named layers.
+        //
+
+        assert_eq!(target.get(1, 1), u8::MAX - 1);
+        assert_eq!(target.get(2, 2), options.road_cost);
+        assert_eq!(target.get(3, 3), u8::MAX);
+        assert_eq!(target.get(4, 4), 60);
+    }
+
+    #[test]
+    fn clear_room_and_clear_cache_remove_rooms() {
+        let storage = seeded_storage(&["W1N1", "W2N1", "E5S3"]);
+        let mut system = sharded_system(&storage);
+
+        system.clear_room(room("W1N1"));
+
+        assert!(!system.get_cache().rooms.contains_key(&room("W1N1")));
+        assert!(system.get_cache().rooms.contains_key(&room("W2N1")));
+
+        system.clear_cache();
+
+        assert!(system.get_cache().rooms.is_empty());
+
+        system.flush_dirty().unwrap();
+
+        assert!(stored_markers(&storage).is_empty());
+    }
+
+    #[test]
+    fn least_recently_accessed_room_is_evicted() {
+        let mut data_source = SnapshotCostMatrixDataSource::new();
+        let mut cache = CostMatrixCache::default();
+        let expiration = CostMatrixExpiration::default();
+
+        cache.set_max_rooms(Some(2));
+
+        for (tick, name) in [(1, "W1N1"), (2, "W2N1"), (3, "W1N1"), (4, "W3N1")].iter() {
+            data_source.set_time(*tick);
+
+            cache.get_room(room(name), &data_source, expiration);
+        }
+
+        assert_eq!(cache.rooms.len(), 2);
+        assert!(cache.rooms.contains_key(&room("W1N1")));
+        assert!(cache.rooms.contains_key(&room("W3N1")));
+        assert!(cache.take_changed_rooms().contains(&room("W2N1")));
+    }
+}
@@ -1,16 +1,20 @@
 mod costmatrix;
+mod costmatrixdatasource;
 mod costmatrixsystem;
 mod error;
 mod location;
 mod movementrequest;
+mod movementresult;
 mod movementsystem;
 mod utility;
 mod constants;
 
 pub use costmatrix::*;
+pub use costmatrixdatasource::*;
 pub use costmatrixsystem::*;
 pub use error::*;
 pub use location::*;
 pub use movementrequest::*;
+pub use movementresult::*;
 pub use movementsystem::*;
 pub use utility::*;
@@ -1,44 +1,122 @@
+use super::costmatrix::*;
 use super::costmatrixsystem::*;
+use super::utility::*;
 use screeps::*;
+use std::hash::{Hash, Hasher};
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Hash)]
 pub enum HostileBehavior {
     Allow,
     HighCost,
     Deny,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct RoomOptions {
     hostile_behavior: HostileBehavior,
+    avoid_rooms: Vec<RoomName>,
+    prefer_rooms: Vec<RoomName>,
+    prefer_room_multiplier: f64,
+    hostile_room_multiplier: f64,
 }
 
 impl RoomOptions {
     pub fn hostile_behavior(&self) -> HostileBehavior {
         self.hostile_behavior
     }
+
+    pub fn avoid_rooms(&self) -> &[RoomName] {
+        &self.avoid_rooms
+    }
+
+    pub fn prefer_rooms(&self) -> &[RoomName] {
+        &self.prefer_rooms
+    }
+
+    pub fn prefer_room_multiplier(&self) -> f64 {
+        self.prefer_room_multiplier
+    }
+
+    pub fn hostile_room_multiplier(&self) -> f64 {
+        self.hostile_room_multiplier
+    }
+
+    /// Route cost of a hostile room, or `None` if it can't be entered.
+    pub fn hostile_room_cost(&self) -> Option<f64> {
+        match self.hostile_behavior {
+            HostileBehavior::Allow => Some(1.0),
+            HostileBehavior::HighCost => Some(self.hostile_room_multiplier),
+            HostileBehavior::Deny => None,
+        }
+    }
 }
 
 impl RoomOptions {
     pub fn new(hostile_behavior: HostileBehavior) -> RoomOptions {
-        Self { hostile_behavior }
+        Self {
+            hostile_behavior,
+            ..Default::default()
+        }
+    }
+
+    /// Rooms that routes never pass through, whatever their status.
+    pub fn with_avoid_rooms(mut self, rooms: Vec<RoomName>) -> RoomOptions {
+        self.avoid_rooms = rooms;
+        self
+    }
+
+    /// Rooms whose route cost is scaled by `multiplier` so routes are biased through them.
+    pub fn with_prefer_rooms(mut self, rooms: Vec<RoomName>, multiplier: f64) -> RoomOptions {
+        self.prefer_rooms = rooms;
+        self.prefer_room_multiplier = multiplier;
+        self
+    }
+
+    /// Scales the route cost of hostile rooms when using `HostileBehavior::HighCost`.
+    pub fn with_hostile_room_multiplier(mut self, multiplier: f64) -> RoomOptions {
+        self.hostile_room_multiplier = multiplier;
+        self
     }
 }
 
 impl Default for RoomOptions {
     fn default() -> Self {
         RoomOptions {
-            hostile_behavior: HostileBehavior::Deny,
+            hostile_behavior: HostileBehavior::Allow,
+            avoid_rooms: Vec::new(),
+            prefer_rooms: Vec::new(),
+            prefer_room_multiplier: 0.5,
+            hostile_room_multiplier: 10.0,
         }
     }
 }
 
+impl Hash for RoomOptions {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.hostile_behavior.hash(state);
+        self.avoid_rooms.hash(state);
+        self.prefer_rooms.hash(state);
+        self.prefer_room_multiplier.to_bits().hash(state);
+        self.hostile_room_multiplier.to_bits().hash(state);
+    }
+}
+
 pub struct MovementRequest {
     pub(crate) destination: RoomPosition,
     pub(crate) range: u32,
     pub(crate) room_options: Option<RoomOptions>,
     pub(crate) cost_matrix_options: Option<CostMatrixOptions>,
     pub(crate) visualization: Option<PolyStyle>,
+    pub(crate) adjacent_to_blocked: bool,
+    pub(crate) pre_search_augment: Option<Box<dyn Fn(RoomName, &mut LinearCostMatrix)>>,
+    pub(crate) pre_search_augment_key: Option<u64>,
+    pub(crate) flee: bool,
+    pub(crate) additional_goals: Vec<(RoomPosition, u32)>,
+    pub(crate) deadline: Option<u32>,
+    pub(crate) park_off_road: bool,
+    pub(crate) first_step: Option<Direction>,
+    pub(crate) label: Option<&'static str>,
+    pub(crate) repath_cooldown: Option<u32>,
 }
 
 impl MovementRequest {
@@ -49,42 +127,187 @@ impl MovementRequest {
             room_options: None,
             cost_matrix_options: None,
             visualization: None,
+            adjacent_to_blocked: false,
+            pre_search_augment: None,
+            pre_search_augment_key: None,
+            flee: false,
+            additional_goals: Vec::new(),
+            deadline: None,
+            park_off_road: false,
+            first_step: None,
+            label: None,
+            repath_cooldown: None,
+        }
+    }
+
+    pub fn flee(threat: RoomPosition, additional_threats: Vec<RoomPosition>, range: u32) -> MovementRequest {
+        let mut request = MovementRequest::move_to(threat);
+
+        request.range = range;
+        request.flee = true;
+        request.additional_goals = additional_threats.into_iter().map(|pos| (pos, range)).collect();
+
+        request
+    }
+
+    pub fn move_to_any(
+        destination: RoomPosition,
+        range: u32,
+        additional_goals: Vec<(RoomPosition, u32)>,
+    ) -> MovementRequest {
+        let mut request = MovementRequest::move_to(destination);
+
+        request.range = range;
+        request.additional_goals = additional_goals;
+
+        request
+    }
+
+    pub(crate) fn goals(&self) -> impl Iterator<Item = (RoomPosition, u32)> + '_ {
+        std::iter::once((self.destination, self.range)).chain(self.additional_goals.iter().cloned())
+    }
+
+    pub(crate) fn is_valid(&self) -> bool {
+        //
+        // Flee threats may be spread over rooms, other goals must share the destination room.
+        //
+
+        self.goals().all(|(goal, _)| {
+            is_valid_room_position(goal) && (self.flee || goal.room_name() == self.destination.room_name())
+        })
+    }
+
+    pub(crate) fn apply_adjacent_to_blocked(&mut self) {
+        self.apply_adjacent_to_blocked_with(is_tile_walkable);
+    }
+
+    fn apply_adjacent_to_blocked_with<F>(&mut self, is_walkable: F)
+    where
+        F: Fn(RoomPosition) -> bool,
+    {
+        if self.adjacent_to_blocked && self.range == 0 && !is_walkable(self.destination) {
+            self.range = 1;
         }
     }
 }
 
 pub struct MovementRequestBuilder<'a> {
-    request: &'a mut MovementRequest,
+    request: Option<&'a mut MovementRequest>,
 }
 
 impl<'a> Into<MovementRequestBuilder<'a>> for &'a mut MovementRequest {
     fn into(self) -> MovementRequestBuilder<'a> {
-        MovementRequestBuilder { request: self }
+        MovementRequestBuilder {
+            request: Some(self),
+        }
     }
 }
 
 impl<'a> MovementRequestBuilder<'a> {
-    pub fn range(&mut self, range: u32) -> &mut Self {
-        self.request.range = range;
+    pub(crate) fn rejected() -> MovementRequestBuilder<'a> {
+        MovementRequestBuilder { request: None }
+    }
 
-        self
+    pub fn is_rejected(&self) -> bool {
+        self.request.is_none()
     }
 
-    pub fn room_options(&mut self, options: RoomOptions) -> &mut Self {
-        self.request.room_options = Some(options);
+    fn modify<F>(&mut self, modifier: F) -> &mut Self
+    where
+        F: FnOnce(&mut MovementRequest),
+    {
+        if let Some(request) = self.request.as_mut() {
+            modifier(request);
+        }
 
         self
     }
 
-    pub fn cost_matrix_options(&mut self, options: CostMatrixOptions) -> &mut Self {
-        self.request.cost_matrix_options = Some(options);
+    pub fn range(&mut self, range: u32) -> &mut Self {
+        self.modify(|request| request.range = range)
+    }
 
-        self
+    pub fn room_options(&mut self, options: RoomOptions) -> &mut Self {
+        self.modify(|request| request.room_options = Some(options))
+    }
+
+    pub fn cost_matrix_options(&mut self, options: CostMatrixOptions) -> &mut Self {
+        self.modify(|request| request.cost_matrix_options = Some(options))
     }
 
     pub fn visualization(&mut self, style: PolyStyle) -> &mut Self {
-        self.request.visualization = Some(style);
+        self.modify(|request| request.visualization = Some(style))
+    }
 
-        self
+    pub fn adjacent_to_blocked(&mut self, enabled: bool) -> &mut Self {
+        self.modify(|request| request.adjacent_to_blocked = enabled)
+    }
+
+    /// The key identifies the augmentation, changing it invalidates any cached path.
+    pub fn pre_search_augment<F>(&mut self, key: u64, augment: F) -> &mut Self
+    where
+        F: Fn(RoomName, &mut LinearCostMatrix) + 'static,
+    {
+        self.modify(|request| {
+            request.pre_search_augment = Some(Box::new(augment));
+            request.pre_search_augment_key = Some(key);
+        })
+    }
+
+    pub fn deadline(&mut self, ticks: u32) -> &mut Self {
+        self.modify(|request| request.deadline = Some(ticks))
+    }
+
+    pub fn park_off_road(&mut self, enabled: bool) -> &mut Self {
+        self.modify(|request| request.park_off_road = enabled)
+    }
+
+    pub fn first_step(&mut self, direction: Direction) -> &mut Self {
+        self.modify(|request| request.first_step = Some(direction))
+    }
+
+    pub fn label(&mut self, label: &'static str) -> &mut Self {
+        self.modify(|request| request.label = Some(label))
+    }
+
+    pub fn repath_cooldown(&mut self, ticks: u32) -> &mut Self {
+        self.modify(|request| request.repath_cooldown = Some(ticks))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(x: u32, y: u32) -> RoomPosition {
+        RoomPosition::new(x, y, RoomName::new("W1N1").unwrap())
+    }
+
+    #[test]
+    fn adjacent_to_blocked_only_widens_range_for_blocked_destinations() {
+        let mut request = MovementRequest::move_to(pos(10, 10));
+
+        request.apply_adjacent_to_blocked_with(|_| false);
+
+        assert_eq!(request.range, 0);
+
+        request.adjacent_to_blocked = true;
+
+        request.apply_adjacent_to_blocked_with(|_| true);
+
+        assert_eq!(request.range, 0);
+
+        request.apply_adjacent_to_blocked_with(|_| false);
+
+        assert_eq!(request.range, 1);
+
+        let mut request = MovementRequest::move_to(pos(10, 10));
+
+        request.adjacent_to_blocked = true;
+        request.range = 3;
+
+        request.apply_adjacent_to_blocked_with(|_| false);
+
+        assert_eq!(request.range, 3);
     }
 }
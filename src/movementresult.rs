@@ -0,0 +1,211 @@
+use super::error::*;
+use screeps::*;
+use serde::*;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum MovementFailure {
+    InternalError(MovementError),
+    DeadlineExceeded,
+    PathNotFound,
+    RoomBlocked,
+    StuckTimeout { ticks: u32 },
+}
+
+impl From<MovementError> for MovementFailure {
+    fn from(err: MovementError) -> Self {
+        MovementFailure::InternalError(err)
+    }
+}
+
+impl From<&str> for MovementFailure {
+    fn from(err: &str) -> Self {
+        MovementFailure::InternalError(err.to_owned())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum MovementResult {
+    Moving,
+    /// The goal the creep is in range of. Flee requests report the creep position.
+    Arrived { goal: RoomPosition },
+    Stuck { ticks: u32 },
+    WaitingFatigue { remaining: u32 },
+    Failed(MovementFailure),
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct MovementSummary {
+    pub moving: usize,
+    pub arrived: usize,
+    pub stuck: usize,
+    pub waiting_fatigue: usize,
+    pub failed: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MovementResults<Handle>
+where
+    Handle: Hash + Eq,
+{
+    results: HashMap<Handle, MovementResult>,
+}
+
+impl<Handle> Default for MovementResults<Handle>
+where
+    Handle: Hash + Eq,
+{
+    fn default() -> MovementResults<Handle> {
+        MovementResults::new()
+    }
+}
+
+impl<Handle> MovementResults<Handle>
+where
+    Handle: Hash + Eq,
+{
+    pub fn new() -> MovementResults<Handle> {
+        MovementResults {
+            results: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, entity: Handle, result: MovementResult) {
+        self.results.insert(entity, result);
+    }
+
+    pub fn get(&self, entity: &Handle) -> Option<&MovementResult> {
+        self.results.get(entity)
+    }
+
+    /// Keeps only the results for entities matching `predicate`. Results kept across ticks should
+    /// be pruned of dead creeps with this.
+    pub fn retain<F>(&mut self, predicate: F)
+    where
+        F: Fn(&Handle) -> bool,
+    {
+        self.results.retain(|entity, _| predicate(entity));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Handle, &MovementResult)> {
+        self.results.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = (&Handle, &MovementFailure)> {
+        self.results.iter().filter_map(|(entity, result)| match result {
+            MovementResult::Failed(failure) => Some((entity, failure)),
+            _ => None,
+        })
+    }
+
+    pub fn summary(&self) -> MovementSummary {
+        let mut summary = MovementSummary::default();
+
+        for result in self.results.values() {
+            match result {
+                MovementResult::Moving => summary.moving += 1,
+                MovementResult::Arrived { .. } => summary.arrived += 1,
+                MovementResult::Stuck { .. } => summary.stuck += 1,
+                MovementResult::WaitingFatigue { .. } => summary.waiting_fatigue += 1,
+                MovementResult::Failed(_) => summary.failed += 1,
+            }
+        }
+
+        summary
+    }
+
+    pub fn stuck(&self) -> impl Iterator<Item = (&Handle, u32)> {
+        self.results.iter().filter_map(|(entity, result)| match result {
+            MovementResult::Stuck { ticks } => Some((entity, *ticks)),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mixed_results() -> MovementResults<u32> {
+        let mut results = MovementResults::new();
+
+        results.insert(1, MovementResult::Moving);
+        results.insert(2, MovementResult::Stuck { ticks: 3 });
+        results.insert(3, MovementResult::Failed(MovementFailure::PathNotFound));
+        results.insert(4, MovementResult::WaitingFatigue { remaining: 2 });
+        results.insert(5, MovementResult::Stuck { ticks: 7 });
+        results.insert(6, MovementResult::Failed(MovementFailure::DeadlineExceeded));
+
+        results
+    }
+
+    #[test]
+    fn failed_and_stuck_filter_results() {
+        let results = mixed_results();
+
+        let mut failed: Vec<_> = results.failed().map(|(entity, _)| *entity).collect();
+        failed.sort();
+
+        let mut stuck: Vec<_> = results.stuck().map(|(entity, ticks)| (*entity, ticks)).collect();
+        stuck.sort();
+
+        assert_eq!(failed, vec![3, 6]);
+        assert_eq!(stuck, vec![(2, 3), (5, 7)]);
+    }
+
+    #[test]
+    fn summary_counts_each_outcome() {
+        let summary = mixed_results().summary();
+
+        assert_eq!(summary.moving, 1);
+        assert_eq!(summary.arrived, 0);
+        assert_eq!(summary.stuck, 2);
+        assert_eq!(summary.waiting_fatigue, 1);
+        assert_eq!(summary.failed, 2);
+    }
+
+    #[test]
+    fn retain_prunes_results() {
+        let mut results = mixed_results();
+
+        results.retain(|entity| entity % 2 == 0);
+
+        assert_eq!(results.len(), 3);
+        assert!(results.get(&1).is_none());
+        assert!(results.get(&2).is_some());
+    }
+
+    #[test]
+    fn results_round_trip_through_serialization() {
+        let mut results = mixed_results();
+        let goal = Position::new(25, 30, RoomName::new("W1N1").unwrap());
+
+        results.insert(7, MovementResult::Arrived { goal });
+        results.insert(8, MovementResult::Failed(MovementFailure::InternalError("no creep".to_owned())));
+
+        let serialized = serde_json::to_string(&results).unwrap();
+        let deserialized: MovementResults<u32> = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(deserialized.len(), results.len());
+        assert_eq!(serde_json::to_value(&deserialized).unwrap(), serde_json::to_value(&results).unwrap());
+
+        match deserialized.get(&7) {
+            Some(MovementResult::Arrived { goal: arrived }) => assert_eq!(*arrived, goal),
+            _ => panic!("expected arrived result"),
+        }
+
+        match deserialized.get(&8) {
+            Some(MovementResult::Failed(MovementFailure::InternalError(err))) => assert_eq!(err, "no creep"),
+            _ => panic!("expected internal error"),
+        }
+    }
+}
@@ -1,7 +1,12 @@
+use super::constants::*;
+use super::costmatrix::*;
 use super::costmatrixsystem::*;
 use super::error::*;
+use super::location::*;
 use super::movementrequest::*;
+use super::movementresult::*;
 use super::utility::*;
+use log::*;
 use screeps::pathfinder::*;
 use screeps::*;
 use serde::*;
@@ -16,11 +21,525 @@ pub struct CreepPathData {
     path: Vec<Position>,
     time: u32,
     stuck: u32,
+    #[serde(default)]
+    cost_matrix_options_hash: u64,
+    #[serde(default)]
+    room_options_hash: u64,
+    #[serde(default)]
+    started: Option<u32>,
+    #[serde(default)]
+    route: Vec<RoomName>,
+    #[serde(default)]
+    additional_goals_hash: u64,
+    #[serde(default)]
+    pre_search_augment_hash: u64,
+}
+
+#[derive(Copy, Clone)]
+struct PathOptionsHash {
+    cost_matrix_options: u64,
+    room_options: u64,
+    additional_goals: u64,
+    pre_search_augment: u64,
+}
+
+impl PathOptionsHash {
+    fn new(request: &MovementRequest) -> PathOptionsHash {
+        PathOptionsHash {
+            cost_matrix_options: request
+                .cost_matrix_options
+                .as_ref()
+                .map(hash_value)
+                .unwrap_or_else(|| hash_value(&CostMatrixOptions::default())),
+            room_options: request
+                .room_options
+                .as_ref()
+                .map(hash_value)
+                .unwrap_or_else(|| hash_value(&RoomOptions::default())),
+            additional_goals: hash_value(&request.additional_goals),
+            pre_search_augment: hash_value(&request.pre_search_augment_key),
+        }
+    }
+}
+
+impl CreepPathData {
+    fn new(
+        request: &MovementRequest,
+        path: Vec<Position>,
+        route: Vec<RoomName>,
+        options_hash: PathOptionsHash,
+        started: Option<u32>,
+        stuck: u32,
+    ) -> CreepPathData {
+        CreepPathData {
+            destination: request.destination,
+            range: request.range,
+            path,
+            time: 0,
+            stuck,
+            cost_matrix_options_hash: options_hash.cost_matrix_options,
+            room_options_hash: options_hash.room_options,
+            started: Some(started.unwrap_or_else(game::time)),
+            route,
+            additional_goals_hash: options_hash.additional_goals,
+            pre_search_augment_hash: options_hash.pre_search_augment,
+        }
+    }
+
+    fn started_for(&self, request: &MovementRequest) -> Option<u32> {
+        if self.destination == request.destination && self.range == request.range {
+            self.started
+        } else {
+            None
+        }
+    }
+
+    fn route_for(&self, request: &MovementRequest, options_hash: PathOptionsHash) -> Option<Vec<RoomName>> {
+        //
+        // Room options decide the route, so a route planned under other options is never reused.
+        //
+
+        if self.destination.room_name() == request.destination.room_name()
+            && self.room_options_hash == options_hash.room_options
+            && !self.route.is_empty()
+        {
+            Some(self.route.clone())
+        } else {
+            None
+        }
+    }
+
+    fn matches_options(&self, options_hash: PathOptionsHash) -> bool {
+        self.cost_matrix_options_hash == options_hash.cost_matrix_options
+            && self.room_options_hash == options_hash.room_options
+            && self.additional_goals_hash == options_hash.additional_goals
+            && self.pre_search_augment_hash == options_hash.pre_search_augment
+    }
+
+    fn matches_request(&self, request: &MovementRequest, options_hash: PathOptionsHash) -> bool {
+        self.destination == request.destination
+            && self.range == request.range
+            && self.matches_options(options_hash)
+    }
+
+    fn retarget(&mut self, request: &MovementRequest, creep_pos: Position, options_hash: PathOptionsHash) -> bool {
+        if !self.matches_options(options_hash) {
+            return false;
+        }
+
+        //
+        // Only small moves of the destination reuse the path, larger ones are a new movement.
+        //
+
+        if self.destination.get_range_to(&request.destination) > MAX_RETARGET_DISTANCE {
+            return false;
+        }
+
+        let current_index = match find_path_index(&self.path, creep_pos) {
+            Some(index) => index,
+            None => return false,
+        };
+
+        let destination = request.destination;
+        let range = request.range;
+
+        let reached_offset = self
+            .path
+            .iter()
+            .skip(current_index)
+            .position(|p| p.get_range_to(&destination) <= range);
+
+        if let Some(offset) = reached_offset {
+            self.path.truncate(current_index + offset + 1);
+            self.destination = destination;
+            self.range = range;
+
+            true
+        } else {
+            false
+        }
+    }
+
+    fn validate(&mut self, request: &MovementRequest, creep_pos: Position, options_hash: PathOptionsHash) -> bool {
+        //
+        // A nearby new destination that the cached path already reaches can reuse the path, and keeps
+        // the time the movement started so deadlines still apply.
+        //
+
+        (self.matches_request(request, options_hash) || self.retarget(request, creep_pos, options_hash))
+            && find_path_index(&self.path, creep_pos).is_some()
+    }
+
+    fn record_progress(&mut self, moved: bool) {
+        if moved {
+            self.stuck = 0;
+        } else {
+            self.stuck += 1;
+        }
+    }
+
+    fn needs_stuck_repath(&self) -> bool {
+        //
+        // The stuck count is kept across repaths, so a repath is tried on every second stuck tick.
+        //
+
+        self.stuck >= 2 && self.stuck % 2 == 0
+    }
+
+    /// Frees the heap memory of walked path steps. Walked steps are already dropped from the path,
+    /// so this doesn't change what gets stored.
+    fn compact(&mut self) {
+        //
+        // Only reallocate once the path has shrunk well below its capacity.
+        //
+
+        if self.path.capacity() > 16 && self.path.capacity() >= self.path.len() * 4 {
+            self.path.shrink_to_fit();
+        }
+    }
+
+    fn crosses_exclusions(&self, exclusions: &HashSet<Position>) -> bool {
+        !exclusions.is_empty() && self.path.iter().skip(1).any(|pos| exclusions.contains(pos))
+    }
+
+    fn try_advance(
+        &mut self,
+        request: &MovementRequest,
+        creep_pos: Position,
+        options_hash: PathOptionsHash,
+        reuse_path_length: u32,
+        exclusions: &HashSet<Position>,
+    ) -> Option<Position> {
+        let advanced = self.matches_request(request, options_hash)
+            && self.stuck == 0
+            && self.time + 1 < reuse_path_length
+            && self.path.len() > 2
+            && self.path.get(1) == Some(&creep_pos)
+            && !self.crosses_exclusions(exclusions);
+
+        if !advanced {
+            return None;
+        }
+
+        self.path.remove(0);
+        self.time += 1;
+
+        self.path.get(1).cloned()
+    }
+}
+
+fn apply_exclusions<T>(room_name: RoomName, exclusions: &HashSet<Position>, cost_matrix: &mut T)
+where
+    T: CostMatrixSet,
+{
+    cost_matrix.set_multi(
+        exclusions
+            .iter()
+            .filter(|pos| pos.room_name() == room_name)
+            .map(|pos| (Location::from_coords(pos.x(), pos.y()), u8::MAX)),
+    );
+}
+
+fn is_stuck_timed_out(stuck: u32, stuck_timeout: Option<u32>) -> bool {
+    stuck_timeout.map(|stuck_timeout| stuck > stuck_timeout).unwrap_or(false)
+}
+
+/// Terrain costs used by both the custom and the inbuilt pathfinding, so native `move_to` honors
+/// the request's cost options.
+fn get_hostile_aware_room_cost(is_hostile: bool, room_options: &RoomOptions) -> Option<f64> {
+    if is_hostile {
+        room_options.hostile_room_cost()
+    } else {
+        Some(1.0)
+    }
+}
+
+fn get_terrain_costs(options: &CostMatrixOptions) -> (u8, u8) {
+    (options.plains_cost, options.swamp_cost)
+}
+
+/// Applies the cost matrix layers to the matrix handed to the native pathfinder, or `None` if the
+/// room should be treated as impassable.
+fn apply_inbuilt_cost_matrix<T>(
+    cost_matrix_system: &mut CostMatrixSystem,
+    room_name: RoomName,
+    mut cost_matrix: T,
+    options: &CostMatrixOptions,
+) -> Option<T>
+where
+    T: CostMatrixSet,
+{
+    cost_matrix_system.apply_cost_matrix(room_name, &mut cost_matrix, options).ok()?;
+
+    Some(cost_matrix)
+}
+
+fn expand_threat_rings(threats: Vec<(Position, u32)>) -> Vec<Position> {
+    //
+    // The pathfinder weights all flee goals equally, so heavier threats are expanded into rings of
+    // extra goals around them to push the creep further away.
+    //
+
+    let mut goals = Vec::new();
+
+    for (threat, weight) in threats {
+        goals.push(threat);
+
+        //
+        // Rings wider than the room add no goals.
+        //
+
+        for radius in 1..weight.min(ROOM_SIZE) {
+            let radius = radius as i32;
+
+            for x_offset in -radius..=radius {
+                for y_offset in -radius..=radius {
+                    if x_offset.abs() != radius && y_offset.abs() != radius {
+                        continue;
+                    }
+
+                    let x = threat.x() as i32 + x_offset;
+                    let y = threat.y() as i32 + y_offset;
+
+                    if x >= 0 && x < ROOM_SIZE as i32 && y >= 0 && y < ROOM_SIZE as i32 {
+                        goals.push(Position::new(x as u32, y as u32, threat.room_name()));
+                    }
+                }
+            }
+        }
+    }
+
+    goals
+}
+
+fn find_reached_goal(request: &MovementRequest, creep_pos: Position) -> Option<(Position, u32)> {
+    request.goals().find(|(goal, range)| creep_pos.get_range_to(goal) <= *range)
+}
+
+fn is_clear_of_threats(request: &MovementRequest, creep_pos: Position) -> bool {
+    request.goals().all(|(threat, range)| creep_pos.get_range_to(&threat) >= range)
+}
+
+fn get_flee_status(incomplete: bool) -> MovementResult {
+    //
+    // A cornered creep still steps as far away as it can, but the caller is told it cannot escape.
+    //
+
+    if incomplete {
+        MovementResult::Failed(MovementFailure::PathNotFound)
+    } else {
+        MovementResult::Moving
+    }
+}
+
+fn is_oscillating(last_direction: Option<Direction>, direction: Direction) -> bool {
+    last_direction.map(|last| -last == direction).unwrap_or(false)
+}
+
+fn is_deadline_exceeded(started: u32, now: u32, deadline: u32) -> bool {
+    now.saturating_sub(started) >= deadline
+}
+
+fn get_movement_status(fatigue: u32, stuck: u32) -> MovementResult {
+    if fatigue > 0 {
+        MovementResult::WaitingFatigue { remaining: fatigue }
+    } else if stuck > 0 {
+        MovementResult::Stuck { ticks: stuck }
+    } else {
+        MovementResult::Moving
+    }
+}
+
+fn is_path_expired(path_time: u32, reuse_path_length: u32) -> bool {
+    //
+    // Path time has already been incremented for this tick, so a reuse length of 0 always expires.
+    //
+
+    path_time >= reuse_path_length
+}
+
+fn should_regenerate_path(has_path: bool, path_expired: bool, stuck: bool, cooling_down: bool) -> bool {
+    //
+    // Expired and stuck paths are kept while the repath cooldown is active.
+    //
+
+    !has_path || ((path_expired || stuck) && !cooling_down)
+}
+
+fn check_route_distance(distance: u32, max_route_rooms: u32) -> Result<(), MovementFailure> {
+    if distance > max_route_rooms {
+        Err(MovementFailure::RoomBlocked)
+    } else {
+        Ok(())
+    }
+}
+
+/// A room route can only be missing when every route to the destination passes a blocked room.
+fn check_route<T>(route: Result<T, ReturnCode>) -> Result<T, MovementFailure> {
+    route.map_err(|_| MovementFailure::RoomBlocked)
+}
+
+/// An incomplete search never reached a goal, so the destination is unreachable.
+fn check_search_complete(incomplete: bool) -> Result<(), MovementFailure> {
+    if incomplete {
+        Err(MovementFailure::PathNotFound)
+    } else {
+        Ok(())
+    }
+}
+
+fn label_error(label: Option<&str>, err: MovementError) -> MovementError {
+    match label {
+        Some(label) => format!("{}: {}", label, err),
+        None => err,
+    }
+}
+
+fn label_failure(label: Option<&str>, failure: MovementFailure) -> MovementFailure {
+    match failure {
+        MovementFailure::InternalError(err) => MovementFailure::InternalError(label_error(label, err)),
+        failure => failure,
+    }
+}
+
+fn get_request_result(label: Option<&str>, result: Result<MovementResult, MovementFailure>) -> MovementResult {
+    match result {
+        Ok(result) => result,
+        Err(failure) => MovementResult::Failed(label_failure(label, failure)),
+    }
+}
+
+/// Returns the remainder of `route` from `origin` if it still ends at `destination` and every step
+/// of it is traversable.
+fn reuse_route<F>(route: &[RoomName], origin: RoomName, destination: RoomName, is_traversable: F) -> Option<Vec<RoomName>>
+where
+    F: Fn(RoomName, RoomName) -> bool,
+{
+    let start = route.iter().position(|room_name| *room_name == origin)?;
+    let route = &route[start..];
+
+    let valid = route.last() == Some(&destination) && route.windows(2).all(|rooms| is_traversable(rooms[0], rooms[1]));
+
+    if valid {
+        Some(route.to_vec())
+    } else {
+        None
+    }
+}
+
+fn get_spawn_exit_position(creep_pos: Position, destination: Position) -> Option<Position> {
+    let room = game::rooms::get(creep_pos.room_name())?;
+
+    let spawns = room
+        .find(find::MY_SPAWNS)
+        .iter()
+        .map(|spawn| spawn.pos())
+        .collect::<Vec<_>>();
+
+    let is_near_spawn = |pos: &Position| spawns.iter().any(|spawn| spawn.get_range_to(pos) <= 1);
+
+    if !is_near_spawn(&creep_pos) {
+        return None;
+    }
+
+    get_adjacent_positions(creep_pos)
+        .into_iter()
+        .filter(|pos| {
+            !is_near_spawn(pos) && !is_room_edge(*pos) && is_tile_walkable(*pos) && pos.look_for(look::CREEPS).is_empty()
+        })
+        .min_by_key(|pos| pos.get_range_to(&destination))
+}
+
+/// Finds an adjacent tile off the room edge and within range of the goal that `is_free` accepts.
+fn find_park_position<F>(creep_pos: Position, goal: Position, range: u32, is_free: F) -> Option<Position>
+where
+    F: Fn(Position) -> bool,
+{
+    get_adjacent_positions(creep_pos)
+        .into_iter()
+        .find(|pos| pos.get_range_to(&goal) <= range && !is_room_edge(*pos) && is_free(*pos))
+}
+
+fn get_forced_step<F>(creep_pos: Position, direction: Direction, is_walkable: F) -> Option<Position>
+where
+    F: Fn(Position) -> bool,
+{
+    get_position_in_direction(creep_pos, direction).filter(|step_pos| is_walkable(*step_pos))
+}
+
+fn is_newly_spawned(ticks_to_live: u32, life_time: u32) -> bool {
+    //
+    // Creeps start with their full lifetime once spawning finishes.
+    //
+
+    ticks_to_live + SPAWN_EXIT_TICKS >= life_time
+}
+
+fn get_creep_life_time(creep: &Creep) -> u32 {
+    if creep.body().iter().any(|part| part.part == Part::Claim) {
+        CREEP_CLAIM_LIFE_TIME
+    } else {
+        CREEP_LIFE_TIME
+    }
+}
+
+fn find_path_index(path: &[Position], creep_pos: Position) -> Option<usize> {
+    path.iter().take(2).position(|p| *p == creep_pos).or_else(|| {
+        //
+        // Stepping onto a room exit moves the creep to the matching edge tile of the next room at the
+        // end of the tick. That tile may or may not be its own step in the path.
+        //
+
+        let exit = *path.get(1)?;
+
+        if exit.room_name() == creep_pos.room_name() || !is_room_edge(exit) || !is_room_edge(creep_pos) {
+            return None;
+        }
+
+        if path.get(2) == Some(&creep_pos) {
+            Some(2)
+        } else if exit.get_range_to(&creep_pos) <= 1 {
+            Some(1)
+        } else {
+            None
+        }
+    })
 }
 
 #[derive(Clone, Serialize, Deserialize, Default)]
 pub struct CreepMovementData {
     path_data: Option<CreepPathData>,
+    #[serde(default)]
+    last_direction: Option<Direction>,
+    /// Game tick until which the cached path is followed without repathing.
+    #[serde(default)]
+    cooldown: u32,
+}
+
+impl CreepMovementData {
+    /// Replaces the cached path. The last direction belongs to the old path, so it is cleared too.
+    fn set_path_data(&mut self, path_data: Option<CreepPathData>) {
+        self.path_data = path_data;
+        self.last_direction = None;
+    }
+
+    fn validate_path(&mut self, request: &MovementRequest, creep_pos: Position, options_hash: PathOptionsHash) {
+        if let Some(path_data) = self.path_data.as_mut() {
+            if !path_data.validate(request, creep_pos, options_hash) {
+                self.set_path_data(None);
+            }
+        }
+    }
+
+    fn arrived(&mut self) {
+        self.last_direction = None;
+    }
+
+    /// A step straight back along the last direction is only oscillation if the cached path was kept
+    /// this tick and the creep made no progress along it.
+    fn is_oscillating_step(&self, direction: Direction, stalled: bool, now: u32) -> bool {
+        stalled && now >= self.cooldown && is_oscillating(self.last_direction, direction)
+    }
 }
 
 #[derive(Default)]
@@ -29,6 +548,9 @@ where
     Handle: Hash + Eq,
 {
     requests: HashMap<Handle, MovementRequest>,
+    pulled: HashSet<Handle>,
+    pulls: HashMap<Handle, Handle>,
+    exclusions: HashSet<Position>,
 }
 
 #[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
@@ -39,366 +561,2276 @@ where
     pub fn new() -> MovementData<Handle> {
         MovementData {
             requests: HashMap::new(),
+            pulled: HashSet::new(),
+            pulls: HashMap::new(),
+            exclusions: HashSet::new(),
         }
     }
 
     pub fn move_to(&mut self, entity: Handle, destination: RoomPosition) -> MovementRequestBuilder {
+        if !is_valid_room_position(destination) {
+            warn!("Rejected movement request to invalid destination: {:?}", destination);
+
+            self.requests.remove(&entity);
+
+            return MovementRequestBuilder::rejected();
+        }
+
         self.requests
             .entry(entity)
             .and_modify(|e| *e = MovementRequest::move_to(destination))
             .or_insert_with(|| MovementRequest::move_to(destination))
             .into()
     }
-}
 
-pub trait MovementSystemExternal<Handle> {
-    fn get_creep(&self, entity: Handle) -> Result<Creep, MovementError>;
+    /// Moves to whichever of `goals` is cheapest to reach. All goals must be in the same room, as
+    /// the room route is planned to that room.
+    pub fn move_to_any(&mut self, entity: Handle, goals: &[(RoomPosition, u32)]) -> MovementRequestBuilder
+    where
+        Handle: Copy,
+    {
+        let valid_goals = goals.iter().all(|(goal, _)| {
+            is_valid_room_position(*goal) && goal.room_name() == goals[0].0.room_name()
+        });
+
+        if goals.is_empty() || !valid_goals {
+            warn!("Rejected movement request to invalid goals: {:?}", goals);
+
+            self.requests.remove(&entity);
+
+            return MovementRequestBuilder::rejected();
+        }
+
+        let (destination, range) = goals[0];
+
+        let request = MovementRequest::move_to_any(destination, range, goals[1..].to_vec());
+
+        self.insert_request(entity, request)
+    }
+
+    fn insert_request(&mut self, entity: Handle, request: MovementRequest) -> MovementRequestBuilder
+    where
+        Handle: Copy,
+    {
+        self.requests.insert(entity, request);
+
+        self.requests
+            .get_mut(&entity)
+            .map(|request| request.into())
+            .unwrap_or_else(MovementRequestBuilder::rejected)
+    }
+
+    /// Replaces the entity's request, returning the previous one. Invalid requests are rejected
+    /// and remove the previous request, as with `move_to`.
+    pub fn replace(&mut self, entity: Handle, request: MovementRequest) -> Option<MovementRequest> {
+        if !request.is_valid() {
+            warn!("Rejected movement request to invalid destination: {:?}", request.destination);
+
+            return self.requests.remove(&entity);
+        }
+
+        self.requests.insert(entity, request)
+    }
+
+    pub fn flee_from(&mut self, entity: Handle, threats: &[(Position, u32)]) -> MovementRequestBuilder
+    where
+        Handle: Copy,
+    {
+        let valid_threats = threats
+            .iter()
+            .all(|(threat, range)| *range > 0 && is_valid_room_position(*threat));
+
+        if threats.is_empty() || !valid_threats {
+            warn!("Rejected flee request from threats: {:?}", threats);
+
+            self.requests.remove(&entity);
+
+            return MovementRequestBuilder::rejected();
+        }
+
+        let (threat, range) = threats[0];
+
+        let mut request = MovementRequest::flee(threat, Vec::new(), range);
+
+        request.additional_goals = threats[1..].to_vec();
+
+        self.insert_request(entity, request)
+    }
+
+    pub fn flee_from_weighted(
+        &mut self,
+        entity: Handle,
+        threats: Vec<(Position, u32)>,
+        min_range: u32,
+    ) -> MovementRequestBuilder
+    where
+        Handle: Copy,
+    {
+        if min_range == 0 || threats.is_empty() || !threats.iter().all(|(threat, _)| is_valid_room_position(*threat)) {
+            warn!("Rejected flee request with range {} from threats: {:?}", min_range, threats);
+
+            self.requests.remove(&entity);
+
+            return MovementRequestBuilder::rejected();
+        }
+
+        let mut goals = expand_threat_rings(threats).into_iter();
+        let threat = goals.next().unwrap();
+
+        let request = MovementRequest::flee(threat, goals.collect(), min_range);
+
+        self.insert_request(entity, request)
+    }
+
+    pub fn move_many<F>(&mut self, entities: &[Handle], destination: RoomPosition, options: F)
+    where
+        F: Fn(&mut MovementRequestBuilder),
+        Handle: Copy,
+    {
+        for entity in entities {
+            options(&mut self.move_to(*entity, destination));
+        }
+    }
+
+    pub fn mark_pulled(&mut self, entity: Handle) {
+        self.pulled.insert(entity);
+    }
+
+    pub fn is_pulled(&self, entity: &Handle) -> bool {
+        self.pulled.contains(entity)
+    }
+
+    pub fn pull(&mut self, puller: Handle, target: Handle, destination: RoomPosition) -> MovementRequestBuilder
+    where
+        Handle: Copy,
+    {
+        self.pulled.insert(target);
+        self.pulls.insert(puller, target);
+
+        self.move_to(puller, destination)
+    }
+
+    pub fn add_exclusion(&mut self, position: Position) {
+        self.exclusions.insert(position);
+    }
+
+    pub fn add_exclusion_zone(&mut self, positions: &[Position]) {
+        self.exclusions.extend(positions.iter().cloned());
+    }
+
+    pub fn group_by_room<F>(&self, get_pos: F) -> HashMap<RoomName, Vec<Handle>>
+    where
+        F: Fn(&Handle) -> Position,
+        Handle: Copy,
+    {
+        let mut rooms: HashMap<RoomName, Vec<Handle>> = HashMap::new();
+
+        for entity in self.requests.keys() {
+            rooms
+                .entry(get_pos(entity).room_name())
+                .or_insert_with(Vec::new)
+                .push(*entity);
+        }
+
+        rooms
+    }
+}
+
+pub trait MovementSystemExternal<Handle> {
+    fn get_creep(&self, entity: Handle) -> Result<Creep, MovementError>;
+
+    fn get_creep_movement_data(
+        &mut self,
+        entity: Handle,
+    ) -> Result<&mut CreepMovementData, MovementError>;
+
+    /// Clears the movement data of every entity. Implementors that can't enumerate their entities
+    /// keep the default, which reports that clearing isn't supported.
+    fn clear_all_movement_data(&mut self) -> Result<(), MovementError> {
+        Err("Clearing all movement data is not supported".to_owned())
+    }
+
+    fn is_destination_valid(&self, _entity: Handle, _destination: RoomPosition) -> bool {
+        true
+    }
+
+    /// Visible rooms owned by another player are costed by `RoomOptions::hostile_behavior`. The
+    /// default `RoomOptions` allow them at the normal cost.
+    fn get_room_cost(
+        &self,
+        from_room_name: RoomName,
+        to_room_name: RoomName,
+        room_options: &RoomOptions,
+    ) -> Option<f64> {
+        if !can_traverse_between_rooms(from_room_name, to_room_name) {
+            return None;
+        }
+
+        get_hostile_aware_room_cost(is_hostile_room(to_room_name), room_options)
+    }
+
+    /// Distance in rooms checked against `MovementSystem::set_max_route_rooms` before routing.
+    fn get_room_linear_distance(&self, from_room_name: RoomName, to_room_name: RoomName) -> u32 {
+        game::map::get_room_linear_distance(from_room_name, to_room_name, false)
+    }
+}
+
+pub struct MovementSystem<'a, Handle> {
+    cost_matrix_system: &'a mut CostMatrixSystem,
+    default_visualization_style: Option<PolyStyle>,
+    reuse_path_length: u32,
+    max_route_rooms: Option<u32>,
+    stuck_timeout: Option<u32>,
+    phantom: std::marker::PhantomData<Handle>,
+}
+
+#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
+impl<'a, Handle> MovementSystem<'a, Handle>
+where
+    Handle: Hash + Eq + Copy,
+{
+    pub fn new(cost_matrix_system: &'a mut CostMatrixSystem) -> Self {
+        Self {
+            cost_matrix_system,
+            default_visualization_style: None,
+            reuse_path_length: 5,
+            max_route_rooms: None,
+            stuck_timeout: None,
+            phantom: std::marker::PhantomData,
+        }
+    }
+
+    pub fn set_default_visualization_style(&mut self, style: PolyStyle) {
+        self.default_visualization_style = Some(style);
+    }
+
+    /// Number of ticks a generated path is followed before being regenerated. Both 0 and 1
+    /// regenerate the path every tick.
+    pub fn set_reuse_path_length(&mut self, length: u32) {
+        self.reuse_path_length = length;
+    }
+
+    pub fn set_max_route_rooms(&mut self, rooms: Option<u32>) {
+        self.max_route_rooms = rooms;
+    }
+
+    pub fn set_stuck_timeout(&mut self, ticks: Option<u32>) {
+        self.stuck_timeout = ticks;
+    }
+
+    pub fn clear_all_movement_data<S>(&mut self, external: &mut S) -> Result<(), MovementError>
+    where
+        S: MovementSystemExternal<Handle>,
+    {
+        external.clear_all_movement_data()
+    }
+
+    pub fn process_inbuilt<S>(&mut self, external: &mut S, data: MovementData<Handle>) -> MovementResults<Handle>
+    where
+        S: MovementSystemExternal<Handle>,
+    {
+        let mut results = MovementResults::new();
+
+        let MovementData {
+            requests,
+            pulled,
+            pulls,
+            ..
+        } = data;
+
+        for (entity, mut request) in requests.into_iter() {
+            //
+            // Pulled creeps are moved by their puller.
+            //
+
+            if pulled.contains(&entity) {
+                continue;
+            }
+
+            //
+            // Drop requests whose destination became invalid after being queued.
+            //
+
+            if !external.is_destination_valid(entity, request.destination) {
+                continue;
+            }
+
+            let pull_target = Self::get_pull_target(external, &pulls, entity, &mut request);
+
+            let label = request.label;
+
+            let result = match self.process_request_inbuilt(external, entity, request) {
+                Ok(()) => MovementResult::Moving,
+                Err(err) => MovementResult::Failed(MovementFailure::InternalError(label_error(label, err))),
+            };
+
+            if let Some(target) = pull_target {
+                if let MovementResult::Moving = result {
+                    Self::pull_target(external, entity, &target);
+                }
+            }
+
+            results.insert(entity, result);
+        }
+
+        results
+    }
+
+    pub fn process<S>(&mut self, external: &mut S, mut data: MovementData<Handle>) -> MovementResults<Handle>
+    where
+        S: MovementSystemExternal<Handle>,
+    {
+        if data.requests.is_empty() {
+            return MovementResults::default();
+        }
+
+        let mut results = MovementResults::new();
+
+        let entities: Vec<_> = data.requests.keys().cloned().collect();
+
+        for entity in entities {
+            if let Some(result) = self.process_one(external, &mut data, entity) {
+                results.insert(entity, result);
+            }
+        }
+
+        results
+    }
+
+    /// Processes the queued request for a single entity, removing it from `data`. Returns `None` if
+    /// the entity has no request, is pulled by another creep or its destination is no longer valid.
+    pub fn process_one<S>(
+        &mut self,
+        external: &mut S,
+        data: &mut MovementData<Handle>,
+        entity: Handle,
+    ) -> Option<MovementResult>
+    where
+        S: MovementSystemExternal<Handle>,
+    {
+        let mut request = data.requests.remove(&entity)?;
+
+        //
+        // Pulled creeps are moved by their puller.
+        //
+
+        if data.pulled.contains(&entity) {
+            return None;
+        }
+
+        //
+        // Drop requests whose destination became invalid after being queued.
+        //
+
+        if !external.is_destination_valid(entity, request.destination) {
+            return None;
+        }
+
+        let pull_target = Self::get_pull_target(external, &data.pulls, entity, &mut request);
+
+        let label = request.label;
+
+        let result = get_request_result(label, self.process_request(external, entity, request, &data.exclusions));
+
+        //
+        // A stuck puller still issues its move this tick, so the target is pulled along with it.
+        //
+
+        if let Some(target) = pull_target {
+            if let MovementResult::Moving | MovementResult::Stuck { .. } = result {
+                Self::pull_target(external, entity, &target);
+            }
+        }
+
+        Some(result)
+    }
+
+    fn get_pull_target<S>(
+        external: &S,
+        pulls: &HashMap<Handle, Handle>,
+        entity: Handle,
+        request: &mut MovementRequest,
+    ) -> Option<Creep>
+    where
+        S: MovementSystemExternal<Handle>,
+    {
+        let target = pulls.get(&entity).and_then(|target| external.get_creep(*target).ok())?;
+
+        //
+        // Pullers that are not yet adjacent to their target walk to it first.
+        //
+
+        let adjacent = external
+            .get_creep(entity)
+            .map(|puller| puller.pos().is_near_to(&target))
+            .unwrap_or(false);
+
+        if adjacent {
+            Some(target)
+        } else {
+            request.destination = target.pos();
+            request.range = 1;
+            request.additional_goals.clear();
+
+            None
+        }
+    }
+
+    fn pull_target<S>(external: &S, entity: Handle, target: &Creep)
+    where
+        S: MovementSystemExternal<Handle>,
+    {
+        if let Ok(puller) = external.get_creep(entity) {
+            if puller.spawning() {
+                return;
+            }
+
+            let pull_result = puller.pull(target);
+            let pulled_result = target.move_pulled_by(&puller);
+
+            if pull_result != ReturnCode::Ok || pulled_result != ReturnCode::Ok {
+                warn!("Failed to pull creep - pull: {:?} move pulled: {:?}", pull_result, pulled_result);
+            }
+        }
+    }
+
+    fn process_request_inbuilt<S>(
+        &mut self,
+        external: &mut S,
+        entity: Handle,
+        mut request: MovementRequest,
+    ) -> Result<(), MovementError>
+    where
+        S: MovementSystemExternal<Handle>,
+    {
+        let creep = external.get_creep(entity)?;
+
+        if request.flee {
+            return Err("Flee requests are not supported by inbuilt movement".to_owned());
+        }
+
+        request.apply_adjacent_to_blocked();
+
+        let cost_matrix_options = request.cost_matrix_options.clone().unwrap_or_default();
+
+        let (plains_cost, swamp_cost) = get_terrain_costs(&cost_matrix_options);
+
+        let cost_matrix_system = &mut self.cost_matrix_system;
+
+        let move_options = MoveToOptions::new()
+            .range(request.range)
+            .reuse_path(self.reuse_path_length)
+            .plain_cost(plains_cost)
+            .swamp_cost(swamp_cost)
+            .cost_callback(|room_name: RoomName, cost_matrix: CostMatrix| -> SingleRoomCostResult {
+                match apply_inbuilt_cost_matrix(cost_matrix_system, room_name, cost_matrix, &cost_matrix_options) {
+                    Some(cost_matrix) => cost_matrix.into(),
+                    None => SingleRoomCostResult::Impassable,
+                }
+            });
+
+        let vis_move_options = if let Some(vis) = request.visualization.take() {
+            move_options.visualize_path_style(vis)
+        } else if let Some(vis) = self.default_visualization_style.clone() {
+            move_options.visualize_path_style(vis)
+        } else {
+            move_options
+        };
+
+        match creep.move_to_with_options(&request.destination, vis_move_options) {
+            ReturnCode::Ok => return Ok(()),
+            err => return Err(format!("Move error: {:?}", err)),
+        }
+    }
+
+    fn process_request<S>(
+        &mut self,
+        external: &mut S,
+        entity: Handle,
+        mut request: MovementRequest,
+        exclusions: &HashSet<Position>,
+    ) -> Result<MovementResult, MovementFailure>
+    where
+        S: MovementSystemExternal<Handle>,
+    {
+        let creep = external.get_creep(entity)?;
+        let creep_pos = creep.pos();
+        let creep_room_name = creep_pos.room_name();
+
+        request.apply_adjacent_to_blocked();
+
+        if request.flee {
+            return self.process_flee_request(external, entity, &request, &creep, exclusions);
+        }
+
+        //
+        // Don't move if parameters are already met.
+        //
+
+        if find_reached_goal(&request, creep_pos).is_some() {
+            let creep_data = external.get_creep_movement_data(entity)?;
+
+            creep_data.arrived();
+
+            if let Some(cooldown) = request.repath_cooldown {
+                creep_data.cooldown = game::time() + cooldown;
+            }
+
+            return self.arrive(&request, &creep);
+        }
+
+        let options_hash = PathOptionsHash::new(&request);
+
+        //
+        // Keep the cached path in step with the request first, so a retargeted movement keeps its start.
+        //
+
+        external
+            .get_creep_movement_data(entity)?
+            .validate_path(&request, creep_pos, options_hash);
+
+        //
+        // Abandon the movement once it has taken longer than its deadline.
+        //
+
+        if let Some(deadline) = request.deadline {
+            let creep_data = external.get_creep_movement_data(entity)?;
+
+            let started = creep_data.path_data.as_ref().and_then(|path_data| path_data.started_for(&request));
+
+            if let Some(started) = started {
+                if is_deadline_exceeded(started, game::time(), deadline) {
+                    creep_data.set_path_data(None);
+
+                    return Ok(MovementResult::Failed(MovementFailure::DeadlineExceeded));
+                }
+            }
+        }
+
+        let fatigue = creep.fatigue();
+
+        if fatigue == 0 && !creep.spawning() {
+            //
+            // A forced first step is taken before the movement has started.
+            //
+
+            let newly_spawned = is_newly_spawned(creep.ticks_to_live(), get_creep_life_time(&creep));
+
+            let first_step =
+                self.take_first_step(external, entity, &request, creep_pos, newly_spawned, options_hash, exclusions)?;
+
+            //
+            // Fast path - creep advanced a single step along a valid path.
+            //
+
+            let fast_path_step = if first_step.is_some() {
+                None
+            } else {
+                let creep_data = external.get_creep_movement_data(entity)?;
+
+                creep_data.path_data.as_mut().and_then(|path_data| {
+                    path_data.try_advance(
+                        &request,
+                        creep_pos,
+                        options_hash,
+                        self.reuse_path_length,
+                        exclusions,
+                    )
+                })
+            };
+
+            let (next_pos, stalled) = if let Some(next_pos) = first_step {
+                (next_pos, false)
+            } else if let Some(next_pos) = fast_path_step {
+                (next_pos, false)
+            } else if let Some(step) = self.update_path(external, entity, &request, &creep, options_hash, exclusions)? {
+                step
+            } else {
+                external.get_creep_movement_data(entity)?.arrived();
+
+                return self.arrive(&request, &creep);
+            };
+
+            //TODO: This direction is reversed due to a bug in screeps-game-api which reverses the direction calculation.
+            let mut direction = next_pos
+                .get_direction_to(&creep_pos)
+                .ok_or("Expected movement direction")?;
+
+            //
+            // Stepping straight back to the previous tile means the creep is oscillating - repath as if stuck.
+            //
+
+            let oscillating = external
+                .get_creep_movement_data(entity)?
+                .is_oscillating_step(direction, stalled, game::time());
+
+            if oscillating {
+                let cached_route = external
+                    .get_creep_movement_data(entity)?
+                    .path_data
+                    .as_ref()
+                    .and_then(|path_data| path_data.route_for(&request, options_hash));
+
+                let (path_points, route) =
+                    self.generate_path(external, &request, creep_pos, true, exclusions, cached_route)?;
+                let next_pos = path_points.get(1).cloned().ok_or("Expected destination step")?;
+
+                direction = next_pos
+                    .get_direction_to(&creep_pos)
+                    .ok_or("Expected movement direction")?;
+
+                let creep_data = external.get_creep_movement_data(entity)?;
+
+                let started = creep_data.path_data.as_ref().and_then(|path_data| path_data.started_for(&request));
+                let stuck = creep_data.path_data.as_ref().map(|path_data| path_data.stuck).unwrap_or(0);
+
+                creep_data.set_path_data(Some(CreepPathData::new(&request, path_points, route, options_hash, started, stuck)));
+                creep_data.cooldown = game::time() + request.repath_cooldown.unwrap_or(0);
+            }
+
+            match creep.move_direction(direction) {
+                ReturnCode::Ok => Ok(()),
+                err => Err(format!("Movement error: {:?}", err)),
+            }?;
+
+            external.get_creep_movement_data(entity)?.last_direction = Some(direction);
+        }
+
+        let stuck = {
+            let creep_data = external.get_creep_movement_data(entity)?;
+
+            let path_data = if let Some(path_data) = creep_data.path_data.as_mut() {
+                path_data
+            } else {
+                return Ok(get_movement_status(fatigue, 0));
+            };
+
+            let path = &mut path_data.path;
+
+            //
+            // Visualize
+            //
+
+            let visualization = request
+                .visualization
+                .or_else(|| self.default_visualization_style.clone());
+
+            if let Some(visualization) = visualization {
+                let visual = RoomVisual::new(Some(creep_room_name));
+
+                let points = path
+                    .iter()
+                    .take_while(|p| p.room_name() == creep_room_name)
+                    .map(|p| (p.x() as f32, p.y() as f32))
+                    .collect::<Vec<_>>();
+
+                visual.poly(points, Some(visualization));
+            }
+
+            path_data.stuck
+        };
+
+        //
+        // Give up on creeps that have been stuck for too long so the caller can reassign them.
+        //
+
+        if is_stuck_timed_out(stuck, self.stuck_timeout) {
+            external.get_creep_movement_data(entity)?.set_path_data(None);
+
+            return Ok(MovementResult::Failed(MovementFailure::StuckTimeout { ticks: stuck }));
+        }
+
+        Ok(get_movement_status(fatigue, stuck))
+    }
+
+    fn arrive(&self, request: &MovementRequest, creep: &Creep) -> Result<MovementResult, MovementFailure> {
+        let creep_pos = creep.pos();
+
+        //
+        // Report whichever goal the creep is in range of, which may not be the one the path aimed for.
+        //
+
+        let (goal, range) = find_reached_goal(request, creep_pos).unwrap_or((request.destination, request.range));
+
+        if request.park_off_road && creep.fatigue() == 0 && !creep.spawning() {
+            if is_road(creep_pos) {
+                //
+                // Step off the road onto a free adjacent tile that is still within range of the goal.
+                //
+
+                let park_pos = find_park_position(creep_pos, goal, range, |pos| {
+                    !is_road(pos) && is_tile_walkable(pos) && pos.look_for(look::CREEPS).is_empty()
+                });
+
+                if let Some(park_pos) = park_pos {
+                    //TODO: This direction is reversed due to a bug in screeps-game-api which reverses the direction calculation.
+                    let direction = park_pos
+                        .get_direction_to(&creep_pos)
+                        .ok_or("Expected movement direction")?;
+
+                    match creep.move_direction(direction) {
+                        ReturnCode::Ok => Ok(()),
+                        err => Err(format!("Movement error: {:?}", err)),
+                    }?;
+                }
+            }
+        }
+
+        Ok(MovementResult::Arrived { goal })
+    }
+
+    fn process_flee_request<S>(
+        &mut self,
+        external: &mut S,
+        entity: Handle,
+        request: &MovementRequest,
+        creep: &Creep,
+        exclusions: &HashSet<Position>,
+    ) -> Result<MovementResult, MovementFailure>
+    where
+        S: MovementSystemExternal<Handle>,
+    {
+        let creep_pos = creep.pos();
+
+        //
+        // Don't move if already out of range of all threats.
+        //
+
+        if is_clear_of_threats(request, creep_pos) {
+            return Ok(MovementResult::Arrived { goal: creep_pos });
+        }
+
+        let fatigue = creep.fatigue();
+
+        if fatigue > 0 {
+            return Ok(MovementResult::WaitingFatigue { remaining: fatigue });
+        }
+
+        if creep.spawning() {
+            return Ok(MovementResult::Moving);
+        }
+
+        //
+        // Threats move every tick so flee paths are generated fresh and never cached.
+        //
+
+        let (path_points, incomplete) = self.generate_flee_path(request, creep, exclusions)?;
+        let next_pos = path_points.get(1).cloned().ok_or(MovementFailure::PathNotFound)?;
+
+        //TODO: This direction is reversed due to a bug in screeps-game-api which reverses the direction calculation.
+        let direction = next_pos
+            .get_direction_to(&creep_pos)
+            .ok_or("Expected movement direction")?;
+
+        match creep.move_direction(direction) {
+            ReturnCode::Ok => Ok(()),
+            err => Err(format!("Movement error: {:?}", err)),
+        }?;
+
+        let creep_data = external.get_creep_movement_data(entity)?;
+
+        creep_data.set_path_data(None);
+        creep_data.last_direction = Some(direction);
+
+        let visualization = request
+            .visualization
+            .clone()
+            .or_else(|| self.default_visualization_style.clone());
+
+        if let Some(visualization) = visualization {
+            let creep_room_name = creep_pos.room_name();
+
+            let visual = RoomVisual::new(Some(creep_room_name));
+
+            let points = path_points
+                .iter()
+                .take_while(|p| p.room_name() == creep_room_name)
+                .map(|p| (p.x() as f32, p.y() as f32))
+                .collect::<Vec<_>>();
+
+            visual.poly(points, Some(visualization));
+        }
+
+        Ok(get_flee_status(incomplete))
+    }
+
+    fn generate_flee_path(
+        &mut self,
+        request: &MovementRequest,
+        creep: &Creep,
+        exclusions: &HashSet<Position>,
+    ) -> Result<(Vec<Position>, bool), MovementError> {
+        let creep_pos = creep.pos();
+        let creep_room_name = creep_pos.room_name();
+
+        let cost_matrix_options = request.cost_matrix_options.clone().unwrap_or_default();
+
+        let cost_matrix_system = &mut self.cost_matrix_system;
+
+        let pre_search_augment = request.pre_search_augment.as_ref();
+
+        let (plains_cost, swamp_cost) = get_terrain_costs(&cost_matrix_options);
+
+        let search_options = SearchOptions::new()
+            .flee(true)
+            .max_rooms(1)
+            .plain_cost(plains_cost)
+            .swamp_cost(swamp_cost)
+            .room_callback(|room_name: RoomName| -> MultiRoomCostResult {
+                if room_name == creep_room_name {
+                    let mut cost_matrix = CostMatrix::default();
+
+                    match cost_matrix_system.apply_cost_matrix(
+                        room_name,
+                        &mut cost_matrix,
+                        &cost_matrix_options,
+                    ) {
+                        Ok(()) => {
+                            if let Some(pre_search_augment) = pre_search_augment {
+                                let mut augmentation = LinearCostMatrix::new();
+
+                                pre_search_augment(room_name, &mut augmentation);
+
+                                augmentation.apply_to(&mut cost_matrix);
+                            }
+
+                            apply_exclusions(room_name, exclusions, &mut cost_matrix);
+
+                            cost_matrix.into()
+                        }
+                        Err(_err) => MultiRoomCostResult::Impassable,
+                    }
+                } else {
+                    MultiRoomCostResult::Impassable
+                }
+            });
+
+        //
+        // An incomplete flee search still moves the creep as far from the threats as it can get.
+        //
+
+        let search_result = pathfinder::search_many(&creep_pos, request.goals(), search_options);
+
+        let incomplete = search_result.incomplete;
+
+        let mut path_points = search_result.load_local_path();
+
+        path_points.insert(0, creep_pos);
+
+        Ok((path_points, incomplete))
+    }
+
+    fn take_first_step<S>(
+        &mut self,
+        external: &mut S,
+        entity: Handle,
+        request: &MovementRequest,
+        creep_pos: Position,
+        newly_spawned: bool,
+        options_hash: PathOptionsHash,
+        exclusions: &HashSet<Position>,
+    ) -> Result<Option<Position>, MovementFailure>
+    where
+        S: MovementSystemExternal<Handle>,
+    {
+        //
+        // The forced step is only taken when the movement starts.
+        //
+
+        let (started, has_path) = {
+            let creep_data = external.get_creep_movement_data(entity)?;
+
+            let started = creep_data.path_data.as_ref().and_then(|path_data| path_data.started_for(request)).is_some();
+
+            (started, creep_data.path_data.is_some())
+        };
+
+        if started {
+            return Ok(None);
+        }
+
+        let step_pos = if let Some(first_step) = request.first_step {
+            match get_forced_step(creep_pos, first_step, is_tile_walkable) {
+                Some(step_pos) => step_pos,
+                None => return Ok(None),
+            }
+        } else if newly_spawned && !has_path {
+            //
+            // Creeps that just finished spawning step clear of the spawn first so they don't block the next spawn.
+            // Movement data alone can't tell this, as it is also missing after being cleared.
+            //
+
+            match get_spawn_exit_position(creep_pos, request.destination) {
+                Some(step_pos) => step_pos,
+                None => return Ok(None),
+            }
+        } else {
+            return Ok(None);
+        };
+
+        //
+        // Path onwards from the forced step so the cached path continues from where the creep will be.
+        //
+
+        let (mut path_points, route) = self.generate_path(external, request, step_pos, false, exclusions, None)?;
+
+        path_points.insert(0, creep_pos);
+
+        let creep_data = external.get_creep_movement_data(entity)?;
+
+        creep_data.set_path_data(Some(CreepPathData::new(request, path_points, route, options_hash, None, 0)));
+
+        Ok(Some(step_pos))
+    }
+
+    /// Returns the next step, and whether the cached path was kept while the creep made no progress.
+    fn update_path<S>(
+        &mut self,
+        external: &mut S,
+        entity: Handle,
+        request: &MovementRequest,
+        creep: &Creep,
+        options_hash: PathOptionsHash,
+        exclusions: &HashSet<Position>,
+    ) -> Result<Option<(Position, bool)>, MovementFailure>
+    where
+        S: MovementSystemExternal<Handle>,
+    {
+        let creep_pos = creep.pos();
+
+        //
+        // Invalidate path if parameters have changed.
+        //
+
+        let (has_path, started, cached_route, cooling_down) = {
+            let creep_data = external.get_creep_movement_data(entity)?;
+
+            let started = creep_data.path_data.as_ref().and_then(|path_data| path_data.started_for(request));
+            let cached_route = creep_data.path_data.as_ref().and_then(|path_data| path_data.route_for(request, options_hash));
+
+            creep_data.validate_path(request, creep_pos, options_hash);
+
+            let cooling_down = game::time() < creep_data.cooldown;
+
+            (creep_data.path_data.is_some(), started, cached_route, cooling_down)
+        };
+
+        //
+        // Calculate if creep moved since last tick.
+        //
+
+        let move_result = {
+            let creep_data = external.get_creep_movement_data(entity)?;
+
+            if let Some(path_data) = creep_data.path_data.as_mut() {
+                path_data.time += 1;
+
+                let path = &mut path_data.path;
+
+                let current_index = find_path_index(path, creep_pos).ok_or("Expected current position in path")?;
+
+                let moved = current_index > 0;
+
+                path.drain(..current_index);
+
+                if path.len() == 1 {
+                    return Ok(None);
+                }
+
+                //
+                // Single steps are taken on the fast path, so only larger trims here are worth compacting.
+                //
+
+                if current_index > 1 {
+                    path_data.compact();
+                }
+
+                path_data.record_progress(moved);
+
+                Some((path_data.time, path_data.stuck, path_data.needs_stuck_repath(), moved))
+            } else {
+                None
+            }
+        };
+
+        let (path_expired, stuck_count, stuck, moved) = match move_result {
+            Some((path_time, stuck_count, stuck, moved)) => {
+                (is_path_expired(path_time, self.reuse_path_length), stuck_count, stuck, moved)
+            }
+            None => (false, 0, false, false),
+        };
+
+        //
+        // Exclusions only last for this tick, so a kept path through an excluded tile is replaced even while cooling down.
+        //
+
+        let excluded = external
+            .get_creep_movement_data(entity)?
+            .path_data
+            .as_ref()
+            .map(|path_data| path_data.crosses_exclusions(exclusions))
+            .unwrap_or(false);
+
+        //
+        // Generate path if required.
+        //
+
+        let new_data = if should_regenerate_path(has_path && !excluded, path_expired, stuck, cooling_down) {
+            let (path_points, route) =
+                self.generate_path(external, request, creep.pos(), stuck, exclusions, cached_route)?;
+
+            Some(CreepPathData::new(request, path_points, route, options_hash, started, stuck_count))
+        } else {
+            None
+        };
+
+        //
+        // Path is generated at this point - find the next step.
+        //
+
+        let creep_data = external.get_creep_movement_data(entity)?;
+
+        let regenerated = new_data.is_some();
+
+        if regenerated {
+            creep_data.set_path_data(new_data);
+            creep_data.cooldown = game::time() + request.repath_cooldown.unwrap_or(0);
+        }
+
+        let path_data = creep_data.path_data.as_ref().ok_or("Expected path data")?;
+
+        let next_pos = path_data.path.get(1).cloned().ok_or("Expected destination step")?;
+
+        Ok(Some((next_pos, !regenerated && !moved)))
+    }
+
+    fn get_route_room_cost<S>(
+        external: &S,
+        from_room_name: RoomName,
+        to_room_name: RoomName,
+        room_options: &RoomOptions,
+    ) -> Option<f64>
+    where
+        S: MovementSystemExternal<Handle>,
+    {
+        if room_options.avoid_rooms().contains(&to_room_name) {
+            return None;
+        }
+
+        let cost = external.get_room_cost(from_room_name, to_room_name, room_options)?;
+
+        if room_options.prefer_rooms().contains(&to_room_name) {
+            Some(cost * room_options.prefer_room_multiplier())
+        } else {
+            Some(cost)
+        }
+    }
+
+    fn generate_path<S>(
+        &mut self,
+        external: &mut S,
+        request: &MovementRequest,
+        origin: Position,
+        is_stuck: bool,
+        exclusions: &HashSet<Position>,
+        cached_route: Option<Vec<RoomName>>,
+    ) -> Result<(Vec<Position>, Vec<RoomName>), MovementFailure>
+    where
+        S: MovementSystemExternal<Handle>,
+    {
+        let origin_room_name = origin.room_name();
+
+        let default_room_options = RoomOptions::default();
+        let room_options = request.room_options.as_ref().unwrap_or(&default_room_options);
+
+        let destination_room = request.destination.room_name();
+
+        if let Some(max_route_rooms) = self.max_route_rooms {
+            let distance = external.get_room_linear_distance(origin_room_name, destination_room);
+
+            check_route_distance(distance, max_route_rooms)?;
+        }
+
+        //
+        // Reuse the remainder of the previous room route while every room on it is still traversable.
+        //
+
+        let cached_route = cached_route.and_then(|route| {
+            reuse_route(&route, origin_room_name, destination_room, |from_room_name, to_room_name| {
+                Self::get_route_room_cost(external, from_room_name, to_room_name, room_options).is_some()
+            })
+        });
+
+        let route = if let Some(route) = cached_route {
+            route
+        } else {
+            let room_path = check_route(game::map::find_route_with_callback(
+                origin_room_name,
+                request.destination.room_name(),
+                |to_room_name, from_room_name| {
+                    Self::get_route_room_cost(external, from_room_name, to_room_name, room_options)
+                        .unwrap_or(f64::INFINITY)
+                },
+            ))?;
+
+            std::iter::once(origin_room_name)
+                .chain(room_path.iter().map(|step| step.room))
+                .collect()
+        };
+
+        let room_names: HashSet<_> = route
+            .iter()
+            .cloned()
+            .chain(request.goals().map(|(goal, _)| goal.room_name()))
+            .collect();
+
+        let mut cost_matrix_options = request.cost_matrix_options.clone().unwrap_or_default();
+
+        if is_stuck {
+            cost_matrix_options.friendly_creeps = true;
+        }
+
+        //
+        // Hostile creeps in hostile rooms are made expensive rather than impassable so paths can still cut through them.
+        //
+
+        let hostile_room_cost_matrix_options = match room_options.hostile_behavior() {
+            HostileBehavior::HighCost if cost_matrix_options.hostile_creep_cost.is_none() => {
+                let mut options = cost_matrix_options.clone();
+
+                options.hostile_creep_cost = Some(u8::MAX - 1);
+
+                Some(options)
+            }
+            _ => None,
+        };
+
+        let cost_matrix_system = &mut self.cost_matrix_system;
+
+        let pre_search_augment = request.pre_search_augment.as_ref();
+
+        let max_ops = room_names.len() as u32 * 2000;
+
+        let (plains_cost, swamp_cost) = get_terrain_costs(&cost_matrix_options);
+
+        let search_options = SearchOptions::new()
+            .max_ops(max_ops)
+            .plain_cost(plains_cost)
+            .swamp_cost(swamp_cost)
+            .room_callback(|room_name: RoomName| -> MultiRoomCostResult {
+                if room_names.contains(&room_name) {
+                    let mut cost_matrix = CostMatrix::default();
+
+                    let room_cost_matrix_options = match &hostile_room_cost_matrix_options {
+                        Some(options) if is_hostile_room(room_name) => options,
+                        _ => &cost_matrix_options,
+                    };
+
+                    match cost_matrix_system.apply_cost_matrix(
+                        room_name,
+                        &mut cost_matrix,
+                        room_cost_matrix_options,
+                    ) {
+                        Ok(()) => {
+                            if let Some(pre_search_augment) = pre_search_augment {
+                                let mut augmentation = LinearCostMatrix::new();
+
+                                pre_search_augment(room_name, &mut augmentation);
+
+                                augmentation.apply_to(&mut cost_matrix);
+                            }
+
+                            apply_exclusions(room_name, exclusions, &mut cost_matrix);
+
+                            cost_matrix.into()
+                        }
+                        Err(_err) => {
+                            //TODO: Surface error?
+                            MultiRoomCostResult::Impassable
+                        }
+                    }
+                } else {
+                    MultiRoomCostResult::Impassable
+                }
+            });
+
+        //
+        // Requests with several goals path to whichever goal is cheapest to reach.
+        //
+
+        let search_result = pathfinder::search_many(&origin, request.goals(), search_options);
+
+        //TODO: Increment stuck, handle stuck? Increase number of ops?
+        check_search_complete(search_result.incomplete)?;
+
+        let mut path_points = search_result.load_local_path();
+
+        path_points.insert(0, origin);
+
+        Ok((path_points, route))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    struct NoStorage;
+
+    impl CostMatrixStorage for NoStorage {
+        fn get_cache(&self, _segment: u32) -> Result<CostMatrixCache, String> {
+            Err("no storage".to_owned())
+        }
+
+        fn set_cache(&mut self, _segment: u32, _data: &CostMatrixCache) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    fn cost_matrix_system() -> CostMatrixSystem {
+        CostMatrixSystem::with_data_source(Box::new(NoStorage), 0, Box::new(SnapshotCostMatrixDataSource::new()))
+    }
+
+    #[derive(Default)]
+    struct TestExternal {
+        movement_data: HashMap<u32, CreepMovementData>,
+        invalid_destinations: HashSet<u32>,
+        hostile_rooms: HashSet<RoomName>,
+        room_distance: u32,
+        calls: Cell<u32>,
+    }
+
+    impl MovementSystemExternal<u32> for TestExternal {
+        fn get_creep(&self, entity: u32) -> Result<Creep, MovementError> {
+            self.calls.set(self.calls.get() + 1);
+
+            Err(format!("no creep {}", entity))
+        }
+
+        fn get_creep_movement_data(&mut self, entity: u32) -> Result<&mut CreepMovementData, MovementError> {
+            self.calls.set(self.calls.get() + 1);
+
+            Ok(self.movement_data.entry(entity).or_default())
+        }
+
+        fn clear_all_movement_data(&mut self) -> Result<(), MovementError> {
+            self.movement_data.clear();
+
+            Ok(())
+        }
+
+        fn is_destination_valid(&self, entity: u32, _destination: RoomPosition) -> bool {
+            !self.invalid_destinations.contains(&entity)
+        }
+
+        fn get_room_cost(&self, _from_room_name: RoomName, to_room_name: RoomName, room_options: &RoomOptions) -> Option<f64> {
+            get_hostile_aware_room_cost(self.hostile_rooms.contains(&to_room_name), room_options)
+        }
+
+        fn get_room_linear_distance(&self, _from_room_name: RoomName, _to_room_name: RoomName) -> u32 {
+            self.room_distance
+        }
+    }
+
+    fn failure_message(result: Option<&MovementResult>) -> Option<&str> {
+        match result {
+            Some(MovementResult::Failed(MovementFailure::InternalError(err))) => Some(err.as_str()),
+            _ => None,
+        }
+    }
+
+    fn test_room() -> RoomName {
+        RoomName::new("W1N1").unwrap()
+    }
+
+    fn pos(x: u32, y: u32) -> Position {
+        Position::new(x, y, test_room())
+    }
+
+    fn straight_path(from_x: u32, to_x: u32, y: u32) -> Vec<Position> {
+        (from_x..=to_x).map(|x| pos(x, y)).collect()
+    }
+
+    fn path_data(request: &MovementRequest, path: Vec<Position>) -> CreepPathData {
+        CreepPathData::new(request, path, Vec::new(), PathOptionsHash::new(request), Some(0), 0)
+    }
+
+    #[test]
+    fn stuck_count_survives_repaths_until_timeout() {
+        let request = MovementRequest::move_to(pos(30, 10));
+        let mut data = path_data(&request, straight_path(10, 30, 10));
+        let mut repaths = 0;
+
+        for _ in 0..6 {
+            data.record_progress(false);
+
+            if data.needs_stuck_repath() {
+                repaths += 1;
+
+                data = CreepPathData::new(
+                    &request,
+                    straight_path(10, 30, 10),
+                    Vec::new(),
+                    PathOptionsHash::new(&request),
+                    data.started,
+                    data.stuck,
+                );
+            }
+        }
+
+        assert_eq!(repaths, 3);
+        assert_eq!(data.stuck, 6);
+        assert!(!is_stuck_timed_out(data.stuck, None));
+        assert!(!is_stuck_timed_out(data.stuck, Some(6)));
+        assert!(is_stuck_timed_out(data.stuck, Some(5)));
+
+        data.record_progress(true);
+
+        assert_eq!(data.stuck, 0);
+    }
+
+    #[test]
+    fn process_one_applies_the_same_checks_as_process() {
+        let mut cost_matrix_system = cost_matrix_system();
+        let mut system = MovementSystem::new(&mut cost_matrix_system);
+        let mut external = TestExternal::default();
+        external.invalid_destinations.insert(3);
+
+        let mut data = MovementData::new();
+        data.move_to(1, pos(20, 20));
+        data.move_to(2, pos(20, 20));
+        data.move_to(3, pos(20, 20));
+        data.mark_pulled(2);
+
+        let result = system.process_one(&mut external, &mut data, 1);
+
+        assert_eq!(failure_message(result.as_ref()), Some("no creep 1"));
+        assert!(system.process_one(&mut external, &mut data, 1).is_none());
+        assert!(system.process_one(&mut external, &mut data, 2).is_none());
+        assert!(system.process_one(&mut external, &mut data, 3).is_none());
+        assert!(data.requests.is_empty());
+    }
+
+    #[test]
+    fn small_retarget_reuses_the_cached_path() {
+        let request = MovementRequest::move_to(pos(30, 10));
+        let mut data = path_data(&request, straight_path(10, 30, 10));
+
+        let nearby = MovementRequest::move_to(pos(28, 10));
+
+        assert!(data.validate(&nearby, pos(10, 10), PathOptionsHash::new(&nearby)));
+        assert_eq!(data.path.last(), Some(&pos(28, 10)));
+        assert_eq!(data.destination, pos(28, 10));
+        assert_eq!(data.started, Some(0));
+        assert_eq!(data.started_for(&nearby), Some(0));
+    }
+
+    #[test]
+    fn distant_retarget_needs_a_new_path() {
+        let request = MovementRequest::move_to(pos(30, 10));
+        let mut data = path_data(&request, straight_path(10, 30, 10));
+
+        //
+        // The cached path passes through the new destination, but it is far from the old one.
+        //
+
+        let distant = MovementRequest::move_to(pos(15, 10));
+
+        assert!(!data.validate(&distant, pos(10, 10), PathOptionsHash::new(&distant)));
+        assert_eq!(data.started, Some(0));
+    }
+
+    #[test]
+    fn only_creeps_with_full_lifetime_are_newly_spawned() {
+        assert!(is_newly_spawned(CREEP_LIFE_TIME, CREEP_LIFE_TIME));
+        assert!(is_newly_spawned(CREEP_LIFE_TIME - 1, CREEP_LIFE_TIME));
+        assert!(is_newly_spawned(CREEP_CLAIM_LIFE_TIME, CREEP_CLAIM_LIFE_TIME));
+
+        //
+        // A creep whose movement data was cleared mid-life is not stepped away from a spawn.
+        //
+
+        assert!(!is_newly_spawned(CREEP_LIFE_TIME - 100, CREEP_LIFE_TIME));
+        assert!(!is_newly_spawned(CREEP_CLAIM_LIFE_TIME - 100, CREEP_CLAIM_LIFE_TIME));
+    }
+
+    #[test]
+    fn label_failure_only_labels_internal_errors() {
+        let labelled = label_failure(Some("hauler"), MovementFailure::InternalError("no path".to_owned()));
+
+        assert!(matches!(labelled, MovementFailure::InternalError(err) if err == "hauler: no path"));
+        assert!(matches!(
+            label_failure(Some("hauler"), MovementFailure::PathNotFound),
+            MovementFailure::PathNotFound
+        ));
+        assert!(matches!(
+            label_failure(Some("hauler"), MovementFailure::RoomBlocked),
+            MovementFailure::RoomBlocked
+        ));
+    }
+
+    #[test]
+    fn route_and_search_errors_become_failures() {
+        let route = check_route::<Vec<RoomName>>(Err(ReturnCode::NoPath));
+        let blocked = get_request_result(Some("hauler"), route.map(|_| MovementResult::Moving));
+
+        assert!(matches!(blocked, MovementResult::Failed(MovementFailure::RoomBlocked)));
+
+        let unreachable = get_request_result(Some("hauler"), check_search_complete(true).map(|_| MovementResult::Moving));
+
+        assert!(matches!(unreachable, MovementResult::Failed(MovementFailure::PathNotFound)));
+
+        assert_eq!(check_route(Ok(vec![test_room()])).ok(), Some(vec![test_room()]));
+        assert!(check_search_complete(false).is_ok());
+        assert!(matches!(
+            get_request_result(None, check_search_complete(false).map(|_| MovementResult::Moving)),
+            MovementResult::Moving
+        ));
+    }
+
+    #[test]
+    fn route_cap_fails_path_generation_before_routing() {
+        let mut cost_matrix_system = cost_matrix_system();
+        let mut system = MovementSystem::new(&mut cost_matrix_system);
+        let mut external = TestExternal {
+            room_distance: 12,
+            ..TestExternal::default()
+        };
+
+        system.set_max_route_rooms(Some(3));
+
+        let request = MovementRequest::move_to(Position::new(25, 25, RoomName::new("W13N1").unwrap()));
+        let result = system.generate_path(&mut external, &request, pos(10, 10), false, &HashSet::new(), None);
+
+        assert!(matches!(result, Err(MovementFailure::RoomBlocked)));
+    }
+
+    #[test]
+    fn far_destination_fails_fast_under_route_cap() {
+        assert!(matches!(check_route_distance(12, 3), Err(MovementFailure::RoomBlocked)));
+        assert!(check_route_distance(3, 3).is_ok());
+        assert!(check_route_distance(0, 0).is_ok());
+    }
+
+    #[test]
+    fn move_to_any_requires_goals_in_one_room() {
+        let mut data = MovementData::new();
+        let other_room = Position::new(10, 10, RoomName::new("W2N1").unwrap());
+
+        assert!(data.move_to_any(1, &[(pos(10, 10), 1), (other_room, 1)]).is_rejected());
+        assert!(data.move_to_any(2, &[]).is_rejected());
+        assert!(!data.move_to_any(3, &[(pos(40, 40), 1), (pos(12, 12), 1)]).is_rejected());
+
+        assert!(!data.requests.contains_key(&1));
+
+        //
+        // The nearer goal is kept alongside the first so the search can head for it.
+        //
+
+        let goals: Vec<_> = data.requests[&3].goals().collect();
+
+        assert_eq!(goals, vec![(pos(40, 40), 1), (pos(12, 12), 1)]);
+    }
+
+    fn invalid_pos() -> Position {
+        let valid = pos(10, 10);
+
+        Position::from_packed((valid.packed_repr() & !0xFF00) | (60 << 8))
+    }
+
+    #[test]
+    fn replace_rejects_invalid_requests() {
+        let mut data = MovementData::new();
+
+        assert!(data.replace(1, MovementRequest::move_to(pos(10, 10))).is_none());
+        assert!(data.replace(1, MovementRequest::move_to(pos(20, 20))).is_some());
+
+        let previous = data.replace(1, MovementRequest::move_to(invalid_pos()));
+
+        assert_eq!(previous.map(|request| request.destination), Some(pos(20, 20)));
+        assert!(!data.requests.contains_key(&1));
+
+        let other_room = Position::new(10, 10, RoomName::new("W2N1").unwrap());
+
+        data.replace(2, MovementRequest::move_to_any(pos(10, 10), 1, vec![(other_room, 1)]));
+
+        assert!(!data.requests.contains_key(&2));
+    }
+
+    #[test]
+    fn move_to_rejects_invalid_positions() {
+        let mut data = MovementData::new();
+
+        data.move_to(1, pos(10, 10));
+        assert!(data.move_to(1, invalid_pos()).range(1).is_rejected());
+
+        assert!(!data.requests.contains_key(&1));
+
+        data.move_to(1, pos(0, 10));
+
+        assert!(data.requests.contains_key(&1));
+    }
+
+    #[test]
+    fn clear_all_movement_data_clears_external_data() {
+        let mut cost_matrix_system = cost_matrix_system();
+        let mut system: MovementSystem<u32> = MovementSystem::new(&mut cost_matrix_system);
+        let mut external = TestExternal::default();
+
+        external.get_creep_movement_data(1).unwrap();
+        external.get_creep_movement_data(2).unwrap();
+
+        assert!(system.clear_all_movement_data(&mut external).is_ok());
+        assert!(external.movement_data.is_empty());
+    }
+
+    #[test]
+    fn changing_pre_search_augment_forces_a_repath() {
+        let mut requests = MovementData::new();
+
+        requests.move_to(1, pos(30, 10));
+
+        let data = path_data(&requests.requests[&1], straight_path(10, 30, 10));
+
+        requests.move_to(1, pos(30, 10)).pre_search_augment(1, |_, _| {});
+
+        let request = &requests.requests[&1];
+
+        assert!(!data.matches_request(request, PathOptionsHash::new(request)));
+
+        let data = path_data(request, straight_path(10, 30, 10));
+
+        requests.move_to(1, pos(30, 10)).pre_search_augment(1, |_, matrix| matrix.set(20, 10, 255));
+
+        let request = &requests.requests[&1];
+
+        assert!(data.matches_request(request, PathOptionsHash::new(request)));
+
+        requests.move_to(1, pos(30, 10)).pre_search_augment(2, |_, _| {});
+
+        let request = &requests.requests[&1];
+
+        assert!(!data.matches_request(request, PathOptionsHash::new(request)));
+    }
+
+    #[test]
+    fn changing_cost_matrix_options_forces_a_repath() {
+        let mut request = MovementRequest::move_to(pos(30, 10));
+        let data = path_data(&request, straight_path(10, 30, 10));
+
+        assert!(data.matches_request(&request, PathOptionsHash::new(&request)));
+
+        request.cost_matrix_options = Some(CostMatrixOptions::default());
+
+        assert!(data.matches_request(&request, PathOptionsHash::new(&request)));
+
+        request.cost_matrix_options = Some(CostMatrixOptions {
+            road_cost: 10,
+            ..CostMatrixOptions::default()
+        });
+
+        assert!(!data.matches_request(&request, PathOptionsHash::new(&request)));
+
+        request.cost_matrix_options = Some(CostMatrixOptions {
+            named_layers: vec!["avoid".to_owned()],
+            ..CostMatrixOptions::default()
+        });
+
+        assert!(!data.matches_request(&request, PathOptionsHash::new(&request)));
+    }
+
+    #[test]
+    fn changing_room_options_forces_a_repath() {
+        let mut request = MovementRequest::move_to(pos(30, 10));
+        let data = path_data(&request, straight_path(10, 30, 10));
+
+        request.room_options = Some(RoomOptions::default());
+
+        assert!(data.matches_request(&request, PathOptionsHash::new(&request)));
+
+        request.room_options = Some(RoomOptions::new(HostileBehavior::Deny));
+
+        assert!(!data.matches_request(&request, PathOptionsHash::new(&request)));
+
+        request.room_options = Some(RoomOptions::default().with_avoid_rooms(vec![RoomName::new("W2N1").unwrap()]));
+
+        assert!(!data.matches_request(&request, PathOptionsHash::new(&request)));
+    }
+
+    #[test]
+    fn try_advance_steps_along_the_cached_path() {
+        let request = MovementRequest::move_to(pos(30, 10));
+        let options_hash = PathOptionsHash::new(&request);
+        let mut data = path_data(&request, straight_path(10, 30, 10));
+
+        assert_eq!(data.try_advance(&request, pos(11, 10), options_hash, 5, &HashSet::new()), Some(pos(12, 10)));
+        assert_eq!(data.path.first(), Some(&pos(11, 10)));
+        assert_eq!(data.time, 1);
+
+        //
+        // A creep that didn't reach the next step needs the full validation.
+        //
+
+        assert_eq!(data.try_advance(&request, pos(11, 10), options_hash, 5, &HashSet::new()), None);
+        assert_eq!(data.path.first(), Some(&pos(11, 10)));
+
+        data.stuck = 1;
+
+        assert_eq!(data.try_advance(&request, pos(12, 10), options_hash, 5, &HashSet::new()), None);
+
+        data.stuck = 0;
+        data.time = 4;
+
+        assert_eq!(data.try_advance(&request, pos(12, 10), options_hash, 5, &HashSet::new()), None);
+    }
+
+    #[test]
+    fn group_by_room_groups_requests_by_creep_room() {
+        let other_room = RoomName::new("W2N1").unwrap();
+        let positions: HashMap<u32, Position> = vec![
+            (1, pos(10, 10)),
+            (2, pos(20, 20)),
+            (3, Position::new(10, 10, other_room)),
+        ]
+        .into_iter()
+        .collect();
+
+        let mut data = MovementData::new();
+
+        for entity in positions.keys() {
+            data.move_to(*entity, pos(25, 25));
+        }
+
+        let mut rooms = data.group_by_room(|entity| positions[entity]);
+
+        for entities in rooms.values_mut() {
+            entities.sort_unstable();
+        }
+
+        assert_eq!(rooms.len(), 2);
+        assert_eq!(rooms[&test_room()], vec![1, 2]);
+        assert_eq!(rooms[&other_room], vec![3]);
+    }
+
+    #[test]
+    fn stepping_back_is_oscillating() {
+        assert!(is_oscillating(Some(Direction::Left), Direction::Right));
+        assert!(is_oscillating(Some(Direction::TopRight), Direction::BottomLeft));
+
+        assert!(!is_oscillating(Some(Direction::Left), Direction::Left));
+        assert!(!is_oscillating(Some(Direction::Top), Direction::Right));
+        assert!(!is_oscillating(None, Direction::Right));
+    }
+
+    #[test]
+    fn move_many_applies_options_to_every_entity() {
+        let mut data = MovementData::new();
+
+        data.move_many(&[1, 2, 3], pos(25, 25), |builder| {
+            builder.range(2);
+        });
+
+        assert_eq!(data.requests.len(), 3);
+        assert!(data
+            .requests
+            .values()
+            .all(|request| request.destination == pos(25, 25) && request.range == 2));
+    }
+
+    #[test]
+    fn mark_pulled_marks_only_the_given_entity() {
+        let mut data = MovementData::new();
+
+        data.mark_pulled(1);
+
+        assert!(data.is_pulled(&1));
+        assert!(!data.is_pulled(&2));
+
+        data.pull(3, 2, pos(25, 25));
+
+        assert!(data.is_pulled(&2));
+        assert!(!data.is_pulled(&3));
+    }
+
+    #[test]
+    fn compact_shrinks_only_mostly_unused_paths() {
+        let request = MovementRequest::move_to(pos(49, 10));
+        let mut data = path_data(&request, straight_path(0, 49, 10));
+
+        data.path.truncate(20);
+        data.compact();
+
+        assert!(data.path.capacity() >= 50);
+
+        data.path.truncate(10);
+        data.compact();
+
+        assert_eq!(data.path.len(), 10);
+        assert!(data.path.capacity() < 40);
+
+        let mut data = path_data(&request, straight_path(0, 15, 10));
+
+        data.path.truncate(1);
+        data.compact();
+
+        assert!(data.path.capacity() >= 16);
+    }
+
+    #[test]
+    fn find_path_index_follows_room_exits() {
+        let north = RoomName::new("W1N2").unwrap();
+
+        let path = vec![
+            pos(25, 1),
+            pos(25, 0),
+            Position::new(25, 49, north),
+            Position::new(25, 48, north),
+        ];
+
+        assert_eq!(find_path_index(&path, pos(25, 1)), Some(0));
+        assert_eq!(find_path_index(&path, pos(25, 0)), Some(1));
+        assert_eq!(find_path_index(&path, Position::new(25, 49, north)), Some(2));
+        assert_eq!(find_path_index(&path, Position::new(25, 48, north)), None);
+
+        //
+        // Without its own step, the edge tile of the next room maps to the exit.
+        //
+
+        let path = vec![pos(25, 1), pos(25, 0), Position::new(25, 48, north)];
+
+        assert_eq!(find_path_index(&path, Position::new(25, 49, north)), Some(1));
+        assert_eq!(find_path_index(&path, Position::new(10, 49, north)), None);
+        assert_eq!(find_path_index(&path, pos(24, 0)), None);
+    }
+
+    #[test]
+    fn flee_from_builds_a_flee_request() {
+        let mut data = MovementData::new();
+
+        assert!(!data.flee_from(1, &[(pos(10, 10), 5), (pos(20, 20), 3)]).is_rejected());
+
+        let request = &data.requests[&1];
+
+        assert!(request.flee);
+        assert_eq!(request.destination, pos(10, 10));
+        assert_eq!(request.range, 5);
+        assert_eq!(request.additional_goals, vec![(pos(20, 20), 3)]);
+
+        assert!(data.flee_from(1, &[(pos(10, 10), 0)]).is_rejected());
+        assert!(!data.requests.contains_key(&1));
+
+        assert!(data.flee_from(2, &[]).is_rejected());
+    }
+
+    #[test]
+    fn flee_from_replaces_an_existing_request() {
+        let mut data = MovementData::new();
+
+        data.move_to(1, pos(30, 30)).range(2);
+        data.flee_from(1, &[(pos(10, 10), 5)]).label("fleeing");
+
+        let request = &data.requests[&1];
+
+        assert!(request.flee);
+        assert_eq!(request.destination, pos(10, 10));
+        assert_eq!(request.range, 5);
+        assert_eq!(request.label, Some("fleeing"));
+    }
+
+    #[test]
+    fn exclusions_block_only_tiles_in_the_room() {
+        let mut data: MovementData<u32> = MovementData::new();
+
+        data.add_exclusion(pos(10, 10));
+        data.add_exclusion_zone(&[pos(11, 10), Position::new(12, 10, RoomName::new("W2N1").unwrap())]);
+
+        let mut cost_matrix = LinearCostMatrix::new();
+
+        apply_exclusions(test_room(), &data.exclusions, &mut cost_matrix);
+
+        assert_eq!(cost_matrix.get(10, 10), u8::MAX);
+        assert_eq!(cost_matrix.get(11, 10), u8::MAX);
+        assert_eq!(cost_matrix.get(12, 10), 0);
+    }
+
+    #[test]
+    fn inbuilt_cost_matrix_applies_the_request_layers() {
+        let mut roads = LinearCostMatrix::new();
+        roads.set(11, 10, 1);
+
+        let mut other = LinearCostMatrix::new();
+        other.set(10, 10, u8::MAX);
+
+        let mut data_source = SnapshotCostMatrixDataSource::new();
+
+        data_source.set_structure_costs(
+            test_room(),
+            StuctureCostMatrixCache {
+                roads,
+                other,
+                hostile_structure_aggro: LinearCostMatrix::new(),
+            },
+        );
+
+        let mut cost_matrix_system = CostMatrixSystem::with_data_source(Box::new(NoStorage), 0, Box::new(data_source));
+
+        let options = CostMatrixOptions {
+            road_cost: 3,
+            ..CostMatrixOptions::default()
+        };
+
+        let cost_matrix =
+            apply_inbuilt_cost_matrix(&mut cost_matrix_system, test_room(), LocalCostMatrix::new(), &options).unwrap();
+
+        assert_eq!(cost_matrix.get(10, 10), u8::MAX);
+        assert_eq!(cost_matrix.get(11, 10), 3);
+        assert_eq!(cost_matrix.get(12, 10), 0);
+
+        let without_structures = CostMatrixOptions {
+            structures: false,
+            ..options
+        };
+
+        let cost_matrix =
+            apply_inbuilt_cost_matrix(&mut cost_matrix_system, test_room(), LocalCostMatrix::new(), &without_structures)
+                .unwrap();
+
+        assert_eq!(cost_matrix.get(10, 10), 0);
+        assert_eq!(cost_matrix.get(11, 10), 0);
+    }
+
+    #[test]
+    fn processing_empty_data_makes_no_external_calls() {
+        let mut cost_matrix_system = cost_matrix_system();
+        let mut system = MovementSystem::new(&mut cost_matrix_system);
+        let mut external = TestExternal::default();
+
+        let results = system.process(&mut external, MovementData::new());
+
+        assert!(results.is_empty());
+        assert_eq!(external.calls.get(), 0);
+
+        let mut data = MovementData::new();
+        data.move_to(1, pos(20, 20));
+
+        system.process(&mut external, data);
+
+        assert!(external.calls.get() > 0);
+    }
+
+    #[test]
+    fn deadline_is_exceeded_once_the_budget_is_spent() {
+        assert!(!is_deadline_exceeded(100, 100, 50));
+        assert!(!is_deadline_exceeded(100, 149, 50));
+        assert!(is_deadline_exceeded(100, 150, 50));
+        assert!(is_deadline_exceeded(100, 400, 50));
+
+        //
+        // A start tick in the future, e.g. after a clock reset, doesn't underflow.
+        //
+
+        assert!(!is_deadline_exceeded(200, 100, 50));
+    }
+
+    #[test]
+    fn heavier_threats_expand_into_rings() {
+        assert_eq!(expand_threat_rings(vec![(pos(10, 10), 1)]), vec![pos(10, 10)]);
+
+        let goals = expand_threat_rings(vec![(pos(10, 10), 3), (pos(30, 30), 1)]);
+
+        //
+        // The threat itself, then rings of 8 and 16 tiles at ranges 1 and 2.
+        //
+
+        assert_eq!(goals.len(), 1 + 8 + 16 + 1);
+        assert_eq!(goals[0], pos(10, 10));
+        assert_eq!(goals.last(), Some(&pos(30, 30)));
+        assert_eq!(goals.iter().filter(|goal| goal.get_range_to(&pos(10, 10)) == 1).count(), 8);
+        assert_eq!(goals.iter().filter(|goal| goal.get_range_to(&pos(10, 10)) == 2).count(), 16);
+
+        //
+        // Ring tiles outside the room are dropped.
+        //
+
+        let goals = expand_threat_rings(vec![(pos(0, 0), 2)]);
+
+        assert_eq!(goals, vec![pos(0, 0), pos(0, 1), pos(1, 0), pos(1, 1)]);
+    }
+
+    #[test]
+    fn requests_to_invalid_destinations_are_skipped() {
+        let mut cost_matrix_system = cost_matrix_system();
+        let mut system = MovementSystem::new(&mut cost_matrix_system);
+        let mut external = TestExternal::default();
+        external.invalid_destinations.insert(1);
 
-    fn get_creep_movement_data(
-        &mut self,
-        entity: Handle,
-    ) -> Result<&mut CreepMovementData, MovementError>;
+        let mut data = MovementData::new();
+        data.move_to(1, pos(20, 20));
+        data.move_to(2, pos(20, 20));
 
-    fn get_room_cost(
-        &self,
-        from_room_name: RoomName,
-        to_room_name: RoomName,
-        _room_options: &RoomOptions,
-    ) -> Option<f64> {
-        if !can_traverse_between_rooms(from_room_name, to_room_name) {
-            return None;
-        }
+        let results = system.process(&mut external, data);
 
-        Some(1.0)
+        assert!(results.get(&1).is_none());
+        assert_eq!(failure_message(results.get(&2)), Some("no creep 2"));
+        assert_eq!(external.calls.get(), 1);
     }
-}
 
-pub struct MovementSystem<'a, Handle> {
-    cost_matrix_system: &'a mut CostMatrixSystem,
-    default_visualization_style: Option<PolyStyle>,
-    reuse_path_length: u32,
-    phantom: std::marker::PhantomData<Handle>,
-}
+    #[test]
+    fn parking_picks_a_free_tile_in_range() {
+        let road: HashSet<Position> = straight_path(5, 15, 10).into_iter().collect();
+        let is_plains = |pos: Position| !road.contains(&pos);
 
-#[cfg_attr(feature = "profile", screeps_timing_annotate::timing)]
-impl<'a, Handle> MovementSystem<'a, Handle>
-where
-    Handle: Hash + Eq + Copy,
-{
-    pub fn new(cost_matrix_system: &'a mut CostMatrixSystem) -> Self {
-        Self {
-            cost_matrix_system,
-            default_visualization_style: None,
-            reuse_path_length: 5,
-            phantom: std::marker::PhantomData,
-        }
+        assert_eq!(find_park_position(pos(10, 10), pos(10, 12), 1, is_plains), Some(pos(9, 11)));
+
+        //
+        // Tiles on the road, out of range of the goal or on the room edge are never used.
+        //
+
+        assert_eq!(find_park_position(pos(10, 10), pos(11, 10), 0, is_plains), None);
+        assert_eq!(find_park_position(pos(10, 10), pos(12, 10), 1, is_plains), Some(pos(11, 9)));
+        assert_eq!(find_park_position(pos(1, 10), pos(0, 9), 1, |_| true), Some(pos(1, 9)));
+        assert_eq!(find_park_position(pos(1, 1), pos(0, 0), 1, |_| true), None);
     }
 
-    pub fn set_default_visualization_style(&mut self, style: PolyStyle) {
-        self.default_visualization_style = Some(style);
+    #[test]
+    fn forced_first_step_follows_the_direction_when_walkable() {
+        assert_eq!(get_forced_step(pos(10, 10), Direction::Right, |_| true), Some(pos(11, 10)));
+        assert_eq!(get_forced_step(pos(10, 10), Direction::TopLeft, |_| true), Some(pos(9, 9)));
+
+        assert_eq!(get_forced_step(pos(10, 10), Direction::Right, |_| false), None);
+        assert_eq!(get_forced_step(pos(0, 10), Direction::Left, |_| true), None);
     }
 
-    pub fn set_reuse_path_length(&mut self, length: u32) {
-        self.reuse_path_length = length;
+    #[test]
+    fn fatigue_takes_precedence_over_stuck() {
+        assert!(matches!(get_movement_status(4, 0), MovementResult::WaitingFatigue { remaining: 4 }));
+        assert!(matches!(get_movement_status(4, 2), MovementResult::WaitingFatigue { remaining: 4 }));
+        assert!(matches!(get_movement_status(0, 2), MovementResult::Stuck { ticks: 2 }));
+        assert!(matches!(get_movement_status(0, 0), MovementResult::Moving));
     }
 
-    pub fn process_inbuilt<S>(&mut self, external: &mut S, data: MovementData<Handle>)
-    where
-        S: MovementSystemExternal<Handle>,
-    {
-        for (entity, request) in data.requests.into_iter() {
-            match self.process_request_inbuilt(external, entity, request) {
-                Ok(()) => {}
-                Err(_err) => {}
-            }
-        }
+    #[test]
+    fn waiting_creeps_keep_a_valid_path() {
+        let request = MovementRequest::move_to(pos(30, 10));
+        let options_hash = PathOptionsHash::new(&request);
+
+        let mut creep_data = CreepMovementData {
+            path_data: Some(path_data(&request, straight_path(10, 30, 10))),
+            ..CreepMovementData::default()
+        };
+
+        creep_data.validate_path(&request, pos(10, 10), options_hash);
+
+        assert_eq!(creep_data.path_data.as_ref().map(|path_data| path_data.path.len()), Some(21));
+
+        //
+        // A creep pushed off its path while fatigued drops the path.
+        //
+
+        creep_data.validate_path(&request, pos(10, 20), options_hash);
+
+        assert!(creep_data.path_data.is_none());
     }
 
-    pub fn process<S>(&mut self, external: &mut S, data: MovementData<Handle>)
-    where
-        S: MovementSystemExternal<Handle>,
-    {
-        for (entity, request) in data.requests.into_iter() {
-            match self.process_request(external, entity, request) {
-                Ok(()) => {}
-                //TODO: Do something sensible with this error.
-                Err(_err) => {}
-            }
-        }
+    #[test]
+    fn cached_route_is_reused_while_traversable() {
+        let rooms: Vec<RoomName> = ["W1N1", "W2N1", "W3N1", "W4N1"]
+            .iter()
+            .map(|name| RoomName::new(name).unwrap())
+            .collect();
+
+        assert_eq!(reuse_route(&rooms, rooms[0], rooms[3], |_, _| true), Some(rooms.clone()));
+
+        //
+        // A creep part way along the route keeps the rest of it.
+        //
+
+        assert_eq!(reuse_route(&rooms, rooms[1], rooms[3], |_, _| true), Some(rooms[1..].to_vec()));
+
+        //
+        // Blocked steps ahead of the creep, a new destination room or an origin off the route need a new route.
+        //
+
+        assert_eq!(reuse_route(&rooms, rooms[0], rooms[3], |_, to| to != rooms[2]), None);
+        assert_eq!(reuse_route(&rooms, rooms[2], rooms[3], |_, to| to != rooms[1]), Some(rooms[2..].to_vec()));
+        assert_eq!(reuse_route(&rooms, rooms[0], rooms[2], |_, _| true), None);
+        assert_eq!(reuse_route(&rooms, RoomName::new("W1N2").unwrap(), rooms[3], |_, _| true), None);
     }
 
-    fn process_request_inbuilt<S>(
-        &mut self,
-        external: &mut S,
-        entity: Handle,
-        mut request: MovementRequest,
-    ) -> Result<(), MovementError>
-    where
-        S: MovementSystemExternal<Handle>,
-    {
-        let creep = external.get_creep(entity)?;
+    #[test]
+    fn failures_include_the_request_label() {
+        let mut cost_matrix_system = cost_matrix_system();
+        let mut system = MovementSystem::new(&mut cost_matrix_system);
+        let mut external = TestExternal::default();
 
-        let move_options = MoveToOptions::new()
-            .range(request.range)
-            .reuse_path(self.reuse_path_length);
+        let mut data = MovementData::new();
+        data.move_to(1, pos(20, 20)).label("hauler");
+        data.move_to(2, pos(20, 20));
 
-        let vis_move_options = if let Some(vis) = request.visualization.take() {
-            move_options.visualize_path_style(vis)
-        } else if let Some(vis) = self.default_visualization_style.clone() {
-            move_options.visualize_path_style(vis)
-        } else {
-            move_options
-        };
+        let results = system.process(&mut external, data);
 
-        match creep.move_to_with_options(&request.destination, vis_move_options) {
-            ReturnCode::Ok => return Ok(()),
-            err => return Err(format!("Move error: {:?}", err)),
-        }
+        assert_eq!(failure_message(results.get(&1)), Some("hauler: no creep 1"));
+        assert_eq!(failure_message(results.get(&2)), Some("no creep 2"));
     }
 
-    fn process_request<S>(
-        &mut self,
-        external: &mut S,
-        entity: Handle,
-        request: MovementRequest,
-    ) -> Result<(), MovementError>
-    where
-        S: MovementSystemExternal<Handle>,
-    {
-        let creep = external.get_creep(entity)?;
-        let creep_pos = creep.pos();
-        let creep_room_name = creep_pos.room_name();
+    #[test]
+    fn repath_cooldown_keeps_expired_and_stuck_paths() {
+        assert!(should_regenerate_path(true, true, false, false));
+        assert!(should_regenerate_path(true, false, true, false));
+        assert!(!should_regenerate_path(true, false, false, false));
+
+        assert!(!should_regenerate_path(true, true, false, true));
+        assert!(!should_regenerate_path(true, false, true, true));
 
         //
-        // Don't move if parameters are already met.
+        // A creep without a path always gets one.
         //
 
-        if request.destination == creep_pos {
-            return Ok(());
-        }
+        assert!(should_regenerate_path(false, false, false, true));
+    }
 
-        if creep.fatigue() == 0 && !creep.spawning() {
-            //
-            // Invalidate path if parameters have changed.
-            //
+    #[test]
+    fn zero_reuse_path_length_searches_every_tick() {
+        let request = MovementRequest::move_to(pos(30, 10));
+        let options_hash = PathOptionsHash::new(&request);
+        let mut data = path_data(&request, straight_path(10, 30, 10));
 
-            let has_path = {
-                let creep_data = external.get_creep_movement_data(entity)?;
+        assert_eq!(data.try_advance(&request, pos(11, 10), options_hash, 0, &HashSet::new()), None);
+        assert!(is_path_expired(1, 0));
 
-                if let Some(path_data) = &creep_data.path_data {
-                    let path_valid = path_data.destination == request.destination
-                        && path_data.range == request.range
-                        && path_data.path.iter().take(2).any(|p| *p == creep_pos);
+        assert!(!is_path_expired(1, 5));
+        assert!(is_path_expired(5, 5));
+    }
 
-                    if !path_valid {
-                        creep_data.path_data = None
-                    }
-                }
+    #[test]
+    fn any_goal_in_range_is_reached() {
+        let request = MovementRequest::move_to_any(pos(10, 10), 1, vec![(pos(40, 40), 3)]);
 
-                creep_data.path_data.is_some()
-            };
+        assert_eq!(find_reached_goal(&request, pos(11, 11)), Some((pos(10, 10), 1)));
+        assert_eq!(find_reached_goal(&request, pos(37, 40)), Some((pos(40, 40), 3)));
+        assert_eq!(find_reached_goal(&request, pos(25, 25)), None);
+    }
 
-            //
-            // Calculate if creep moved since last tick.
-            //
+    #[test]
+    fn fleeing_stops_once_clear_of_every_threat() {
+        let request = MovementRequest::flee(pos(10, 10), vec![pos(20, 10)], 5);
 
-            let move_result = {
-                let creep_data = external.get_creep_movement_data(entity)?;
-                
-                if let Some(path_data) = creep_data.path_data.as_mut() {
-                    path_data.time += 1;
+        assert!(!is_clear_of_threats(&request, pos(12, 10)));
+        assert!(!is_clear_of_threats(&request, pos(16, 10)));
+        assert!(is_clear_of_threats(&request, pos(15, 20)));
+
+        //
+        // Additional threats use the request range.
+        //
+
+        assert!(!is_clear_of_threats(&request, pos(17, 13)));
+    }
+
+    #[test]
+    fn cornered_flee_still_moves_but_fails() {
+        assert!(matches!(get_flee_status(false), MovementResult::Moving));
+        assert!(matches!(
+            get_flee_status(true),
+            MovementResult::Failed(MovementFailure::PathNotFound)
+        ));
+    }
 
-                    let path = &mut path_data.path;
+    #[test]
+    fn avoided_rooms_are_impassable_and_preferred_rooms_cheaper() {
+        let external = TestExternal::default();
+        let rooms: Vec<RoomName> = ["W1N1", "W2N1", "W3N1", "W1N2"]
+            .iter()
+            .map(|name| RoomName::new(name).unwrap())
+            .collect();
 
-                    let current_index = path
-                        .iter()
-                        .take(2)
-                        .enumerate()
-                        .find(|(_, p)| **p == creep_pos)
-                        .map(|(index, _)| index)
-                        .ok_or("Expected current position in path")?;
+        let room_options = RoomOptions::default()
+            .with_avoid_rooms(vec![rooms[1]])
+            .with_prefer_rooms(vec![rooms[3]], 0.5);
 
-                    let moved = current_index > 0;
+        let cost = |from, to| MovementSystem::<u32>::get_route_room_cost(&external, from, to, &room_options);
 
-                    path.drain(..current_index);
+        assert_eq!(cost(rooms[0], rooms[1]), None);
+        assert_eq!(cost(rooms[0], rooms[3]), Some(0.5));
+        assert_eq!(cost(rooms[1], rooms[2]), Some(1.0));
 
-                    if path.len() == 1 {
-                        return Ok(());
-                    }
+        //
+        // A cached route through an avoided room is replaced.
+        //
 
-                    if moved {
-                        path_data.stuck = 0;
-                    } else {
-                        path_data.stuck += 1;
-                    }
+        assert_eq!(reuse_route(&rooms[..3], rooms[0], rooms[2], |from, to| cost(from, to).is_some()), None);
+    }
 
-                    Some((path_data.time, path_data.stuck))
-                } else {
-                    None
-                }
-            };
+    #[test]
+    fn heavier_threats_push_the_creep_further_away() {
+        let mut data = MovementData::new();
 
-            let path_expired = move_result.map(|(path_time, _)| path_time >= self.reuse_path_length).unwrap_or(false);
-            let stuck = move_result.map(|(_, stuck_count)| stuck_count > 1).unwrap_or(false);
+        data.flee_from_weighted(1, vec![(pos(10, 10), 1)], 3);
+        data.flee_from_weighted(2, vec![(pos(10, 10), 3)], 3);
 
-            //
-            // Generate path if required.
-            //
+        //
+        // Four tiles away is clear of a light threat, but still in range of a heavy one.
+        //
 
-            let new_data = if !has_path || path_expired || stuck {
-                let path_points = self.generate_path(external, &request, &creep, stuck)?;
+        assert!(is_clear_of_threats(&data.requests[&1], pos(14, 10)));
+        assert!(!is_clear_of_threats(&data.requests[&2], pos(14, 10)));
+        assert!(is_clear_of_threats(&data.requests[&2], pos(16, 10)));
+    }
 
-                Some(CreepPathData {
-                    destination: request.destination,
-                    range: request.range,
-                    path: path_points,
-                    time: 0,
-                    stuck: 0,
-                })
-            } else {
-                None
-            };
+    #[test]
+    fn threat_weight_is_bounded_by_the_room() {
+        let goals = expand_threat_rings(vec![(pos(25, 25), u32::MAX)]);
 
-            //
-            // Path is generated at this point - run movement logic.
-            //
+        assert_eq!(goals.len(), (ROOM_SIZE * ROOM_SIZE) as usize);
+    }
 
-            let creep_data = external.get_creep_movement_data(entity)?;
+    #[test]
+    fn oscillation_needs_a_stalled_creep_on_its_kept_path() {
+        let request = MovementRequest::move_to(pos(30, 10));
 
-            if new_data.is_some() {
-                creep_data.path_data = new_data;
-            }
+        let mut creep_data = CreepMovementData::default();
+        creep_data.set_path_data(Some(path_data(&request, straight_path(10, 30, 10))));
 
-            let path_data = creep_data.path_data.as_mut().ok_or("Expected path data")?;
-            let path = &mut path_data.path;
+        //
+        // The creep stepped right last tick and is now told to step back left.
+        //
 
-            let next_pos = path.get(1).cloned().ok_or("Expected destination step")?;
+        creep_data.last_direction = Some(Direction::Right);
 
-            //TODO: This direction is reversed due to a bug in screeps-game-api which reverses the direction calculation.
-            let direction = next_pos
-                .get_direction_to(&creep_pos)
-                .ok_or("Expected movement direction")?;
+        assert!(creep_data.is_oscillating_step(Direction::Left, true, 500));
+        assert!(!creep_data.is_oscillating_step(Direction::Right, true, 500));
 
-            match creep.move_direction(direction) {
-                ReturnCode::Ok => Ok(()),
-                err => Err(format!("Movement error: {:?}", err)),
-            }?;
-        }
+        //
+        // A creep that moved, or whose path was regenerated this tick, is not oscillating.
+        //
 
-        {
-            let creep_data = external.get_creep_movement_data(entity)?;
-            let path_data = creep_data.path_data.as_mut().ok_or("Expected path data")?;
-            let path = &mut path_data.path;
+        assert!(!creep_data.is_oscillating_step(Direction::Left, false, 500));
 
-            //
-            // Visualize
-            //
+        creep_data.set_path_data(Some(path_data(&request, straight_path(10, 30, 10))));
 
-            let visualization = request
-                .visualization
-                .or_else(|| self.default_visualization_style.clone());
+        assert!(creep_data.last_direction.is_none());
+        assert!(!creep_data.is_oscillating_step(Direction::Left, true, 500));
 
-            if let Some(visualization) = visualization {
-                let visual = RoomVisual::new(Some(creep_room_name));
+        //
+        // A creep that arrived and is sent back the way it came is not oscillating.
+        //
 
-                let points = path
-                    .iter()
-                    .take_while(|p| p.room_name() == creep_room_name)
-                    .map(|p| (p.x() as f32, p.y() as f32))
-                    .collect::<Vec<_>>();
+        creep_data.last_direction = Some(Direction::Right);
+        creep_data.arrived();
 
-                visual.poly(points, Some(visualization));
-            }
-        }
+        assert!(!creep_data.is_oscillating_step(Direction::Left, true, 500));
 
-        Ok(())
+        //
+        // Oscillation repaths are limited by the repath cooldown.
+        //
+
+        creep_data.last_direction = Some(Direction::Right);
+        creep_data.cooldown = 510;
+
+        assert!(!creep_data.is_oscillating_step(Direction::Left, true, 500));
+        assert!(creep_data.is_oscillating_step(Direction::Left, true, 510));
     }
 
-    fn generate_path<S>(
-        &mut self,
-        external: &mut S,
-        request: &MovementRequest,
-        creep: &Creep,
-        is_stuck: bool
-    ) -> Result<Vec<Position>, MovementError>
-    where
-        S: MovementSystemExternal<Handle>,
-    {
-        let creep_pos = creep.pos();
-        let creep_room_name = creep_pos.room_name();
+    #[test]
+    fn cached_route_is_dropped_when_room_options_change() {
+        let mut request = MovementRequest::move_to(pos(30, 10));
+        let route = vec![RoomName::new("W2N1").unwrap(), test_room()];
+        let data = CreepPathData::new(
+            &request,
+            straight_path(10, 30, 10),
+            route.clone(),
+            PathOptionsHash::new(&request),
+            Some(0),
+            0,
+        );
 
-        let room_options = request.room_options.unwrap_or_default();
+        assert_eq!(data.route_for(&request, PathOptionsHash::new(&request)), Some(route));
 
-        let destination_room = request.destination.room_name();
+        request.room_options = Some(RoomOptions::default().with_prefer_rooms(vec![RoomName::new("W3N1").unwrap()], 0.5));
 
-        let room_path = game::map::find_route_with_callback(
-            creep_room_name,
-            request.destination.room_name(),
-            |to_room_name, from_room_name| {
-                external
-                    .get_room_cost(from_room_name, to_room_name, &room_options)
-                    .unwrap_or(f64::INFINITY)
-            },
-        )
-        .map_err(|e| format!("Could not find path between rooms: {:?}", e))?;
+        assert_eq!(data.route_for(&request, PathOptionsHash::new(&request)), None);
+
+        request.room_options = Some(RoomOptions::new(HostileBehavior::HighCost));
+
+        assert_eq!(data.route_for(&request, PathOptionsHash::new(&request)), None);
+    }
 
-        let room_names: HashSet<_> = room_path
+    #[test]
+    fn hostile_behavior_prices_routes_through_hostile_rooms() {
+        let rooms: Vec<RoomName> = ["W1N1", "W2N1", "W3N1"]
             .iter()
-            .map(|step| step.room)
-            .chain(std::iter::once(creep_room_name))
-            .chain(std::iter::once(destination_room))
+            .map(|name| RoomName::new(name).unwrap())
             .collect();
 
-        let mut cost_matrix_options = request.cost_matrix_options.unwrap_or_default();
+        let mut external = TestExternal::default();
+        external.hostile_rooms.insert(rooms[1]);
 
-        if is_stuck {
-            cost_matrix_options.friendly_creeps = true;
-        }
+        let route_cost = |room_options: &RoomOptions| -> Option<f64> {
+            rooms
+                .windows(2)
+                .map(|step| MovementSystem::<u32>::get_route_room_cost(&external, step[0], step[1], room_options))
+                .sum()
+        };
 
-        let cost_matrix_system = &mut self.cost_matrix_system;
+        let allow = route_cost(&RoomOptions::new(HostileBehavior::Allow));
+        let high_cost = route_cost(&RoomOptions::new(HostileBehavior::HighCost).with_hostile_room_multiplier(8.0));
+        let deny = route_cost(&RoomOptions::new(HostileBehavior::Deny));
 
-        let max_ops = room_names.len() as u32 * 2000;
+        assert_eq!(allow, Some(2.0));
+        assert_eq!(high_cost, Some(9.0));
+        assert_eq!(deny, None);
 
-        let search_options = SearchOptions::new()
-            .max_ops(max_ops)
-            .plain_cost(cost_matrix_options.plains_cost)
-            .swamp_cost(cost_matrix_options.swamp_cost)
-            .room_callback(|room_name: RoomName| -> MultiRoomCostResult {
-                if room_names.contains(&room_name) {
-                    let mut cost_matrix = CostMatrix::default();
+        //
+        // Requests without room options keep routing through hostile rooms at the normal cost.
+        //
 
-                    match cost_matrix_system.apply_cost_matrix(
-                        room_name,
-                        &mut cost_matrix,
-                        &cost_matrix_options,
-                    ) {
-                        Ok(()) => cost_matrix.into(),
-                        Err(_err) => {
-                            //TODO: Surface error?
-                            MultiRoomCostResult::Impassable
-                        }
-                    }
-                } else {
-                    MultiRoomCostResult::Impassable
-                }
-            });
+        assert_eq!(route_cost(&RoomOptions::default()), allow);
+    }
 
-        let search_result = pathfinder::search(
-            &creep_pos,
-            &request.destination,
-            request.range,
-            search_options,
-        );
+    #[test]
+    fn cached_path_through_a_new_exclusion_is_regenerated() {
+        let request = MovementRequest::move_to(pos(30, 10));
+        let options_hash = PathOptionsHash::new(&request);
+        let mut data = path_data(&request, straight_path(10, 30, 10));
 
-        if search_result.incomplete {
-            //TODO: Increment stuck, handle stuck? Increase number of ops?
-            return Err("Unable to generate path".to_owned());
-        }
+        let mut exclusions = HashSet::new();
 
-        let mut path_points = search_result.load_local_path();
+        assert!(!data.crosses_exclusions(&exclusions));
 
-        path_points.insert(0, creep_pos);
+        exclusions.insert(pos(20, 10));
+
+        assert!(data.crosses_exclusions(&exclusions));
+
+        //
+        // The fast path doesn't follow the path, and the path is regenerated even during a repath cooldown.
+        //
+
+        assert_eq!(data.try_advance(&request, pos(11, 10), options_hash, 5, &exclusions), None);
+        assert!(should_regenerate_path(!data.crosses_exclusions(&exclusions), false, false, true));
+
+        //
+        // Exclusions behind the creep or in other rooms don't affect the path.
+        //
+
+        let exclusions: HashSet<_> = vec![pos(10, 10), Position::new(20, 10, RoomName::new("W2N1").unwrap())]
+            .into_iter()
+            .collect();
+
+        assert!(!data.crosses_exclusions(&exclusions));
+        assert_eq!(data.try_advance(&request, pos(11, 10), options_hash, 5, &exclusions), Some(pos(12, 10)));
+    }
+
+    #[test]
+    fn stuck_timeout_can_be_cleared() {
+        let mut cost_matrix_system = cost_matrix_system();
+        let mut system: MovementSystem<u32> = MovementSystem::new(&mut cost_matrix_system);
+
+        system.set_stuck_timeout(Some(5));
+
+        assert!(is_stuck_timed_out(6, system.stuck_timeout));
+
+        system.set_stuck_timeout(None);
 
-        Ok(path_points)
+        assert!(!is_stuck_timed_out(6, system.stuck_timeout));
     }
 }
@@ -1,5 +1,8 @@
+use super::constants::*;
 use screeps::game::map::*;
 use screeps::*;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 
 pub fn can_traverse_between_rooms(from: RoomName, to: RoomName) -> bool {
     let from_room_status = game::map::get_room_status(from);
@@ -16,3 +19,116 @@ pub fn can_traverse_between_room_status(from: &MapRoomStatus, to: &MapRoomStatus
         game::map::RoomStatus::Respawn => from.status == game::map::RoomStatus::Respawn,
     }
 }
+
+pub fn is_hostile_room(room_name: RoomName) -> bool {
+    game::rooms::get(room_name)
+        .and_then(|room| room.controller())
+        .map(|controller| !controller.my() && controller.owner_name().is_some())
+        .unwrap_or(false)
+}
+
+pub fn is_valid_room_position(position: Position) -> bool {
+    position.x() < ROOM_SIZE && position.y() < ROOM_SIZE
+}
+
+pub fn is_room_edge(position: Position) -> bool {
+    let x = position.x();
+    let y = position.y();
+
+    x == 0 || y == 0 || x == ROOM_SIZE - 1 || y == ROOM_SIZE - 1
+}
+
+pub fn is_structure_walkable(structure: &Structure) -> bool {
+    match structure {
+        Structure::Rampart(r) => r.my() || r.is_public(),
+        Structure::Road(_) => true,
+        Structure::Container(_) => true,
+        _ => false,
+    }
+}
+
+pub fn is_tile_walkable(position: Position) -> bool {
+    let room_name = position.room_name();
+
+    let terrain = game::map::get_room_terrain(room_name);
+
+    if let Terrain::Wall = terrain.get(position.x(), position.y()) {
+        return false;
+    }
+
+    //
+    // Structures can only be checked in visible rooms.
+    //
+
+    if game::rooms::get(room_name).is_some() {
+        position
+            .look_for(look::STRUCTURES)
+            .iter()
+            .all(is_structure_walkable)
+    } else {
+        true
+    }
+}
+
+pub fn is_road(position: Position) -> bool {
+    game::rooms::get(position.room_name()).is_some()
+        && position
+            .look_for(look::STRUCTURES)
+            .iter()
+            .any(|structure| matches!(structure, Structure::Road(_)))
+}
+
+pub fn get_adjacent_positions(position: Position) -> Vec<Position> {
+    let x = position.x() as i32;
+    let y = position.y() as i32;
+
+    let mut positions = Vec::with_capacity(8);
+
+    for x_offset in -1..=1 {
+        for y_offset in -1..=1 {
+            let adjacent_x = x + x_offset;
+            let adjacent_y = y + y_offset;
+
+            if (x_offset != 0 || y_offset != 0)
+                && adjacent_x >= 0
+                && adjacent_x < ROOM_SIZE as i32
+                && adjacent_y >= 0
+                && adjacent_y < ROOM_SIZE as i32
+            {
+                positions.push(Position::new(adjacent_x as u32, adjacent_y as u32, position.room_name()));
+            }
+        }
+    }
+
+    positions
+}
+
+pub fn get_position_in_direction(position: Position, direction: Direction) -> Option<Position> {
+    let (x_offset, y_offset) = match direction {
+        Direction::Top => (0, -1),
+        Direction::TopRight => (1, -1),
+        Direction::Right => (1, 0),
+        Direction::BottomRight => (1, 1),
+        Direction::Bottom => (0, 1),
+        Direction::BottomLeft => (-1, 1),
+        Direction::Left => (-1, 0),
+        Direction::TopLeft => (-1, -1),
+    };
+
+    let x = position.x() as i32 + x_offset;
+    let y = position.y() as i32 + y_offset;
+
+    if x >= 0 && x < ROOM_SIZE as i32 && y >= 0 && y < ROOM_SIZE as i32 {
+        Some(Position::new(x as u32, y as u32, position.room_name()))
+    } else {
+        None
+    }
+}
+
+pub(crate) fn hash_value<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+
+    value.hash(&mut hasher);
+
+    hasher.finish()
+}